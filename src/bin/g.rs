@@ -1,10 +1,12 @@
+use giti::completions;
+use giti::dispatch;
 use giti::git;
 use giti::ErrorKind;
 use self_update::cargo_crate_version;
 use std::env;
 use std::process;
 
-fn update() -> Result<(), Box<dyn (::std::error::Error)>> {
+fn update() -> Result<(), Box<dyn ::std::error::Error>> {
     let target = self_update::get_target();
     self_update::backends::github::Update::configure()
         .repo_owner("SirVer")
@@ -23,18 +25,49 @@ fn update() -> Result<(), Box<dyn (::std::error::Error)>> {
 #[tokio::main]
 async fn main() {
     let args_owned: Vec<String> = env::args().collect();
-    let args: Vec<&str> = args_owned.iter().map(|s| s as &str).collect();
+    let mut args: Vec<&str> = args_owned.iter().map(|s| s as &str).collect();
+
+    // 'g --verbose <command>' echoes every command that is run and prints what caused an error.
+    // A lone 'g -v' is left to git, which prints its version.
+    let verbose = args.len() > 1 && (args[1] == "--verbose" || (args[1] == "-v" && args.len() > 2));
+    if verbose {
+        args.remove(1);
+        dispatch::set_verbose(true);
+    }
 
     if args.len() > 1 && args[1] == "--update" {
         update().unwrap();
         return;
     }
+    // Hidden, used as 'source <(g --completions bash)'.
+    if args.len() > 1 && args[1] == "--completions" {
+        match completions::script(args.get(2).copied().unwrap_or("")) {
+            Ok(script) => print!("{}", script),
+            Err(err) => {
+                println!("{}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
     let result = git::handle_repository(&args[1..]).await;
 
     let exit_code = match result {
         Err(error) => {
             match error.kind {
-                ErrorKind::GeneralError => println!("{}", error.description()),
+                ErrorKind::GeneralError | ErrorKind::DiffbaseCycle => {
+                    println!("{}", error.description());
+                    if verbose {
+                        // An error converted from another one has the same description.
+                        for cause in error
+                            .causes()
+                            .into_iter()
+                            .skip_while(|c| c == error.description())
+                        {
+                            println!("Caused by: {}", cause);
+                        }
+                    }
+                }
                 ErrorKind::SubcommandFailed => {}
                 ErrorKind::BranchCantBeDiffbase => panic!("This should already be handled."),
             };
@@ -0,0 +1,164 @@
+use crate::error::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::env;
+
+const BITBUCKET_BASE_URL: &str = "https://api.bitbucket.org/2.0";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum PullRequestState {
+    #[serde(rename = "OPEN")]
+    Open,
+    #[serde(rename = "MERGED")]
+    Merged,
+    #[serde(rename = "DECLINED")]
+    Declined,
+    #[serde(rename = "SUPERSEDED")]
+    Superseded,
+}
+
+#[derive(Deserialize, Debug)]
+struct LinkJson {
+    href: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct LinksJson {
+    html: LinkJson,
+}
+
+#[derive(Deserialize, Debug)]
+struct PullRequestJson {
+    id: usize,
+    state: PullRequestState,
+    links: LinksJson,
+}
+
+#[derive(Debug)]
+pub struct PullRequest {
+    pub state: PullRequestState,
+    pub repo: RepoId,
+    pub number: usize,
+    pub web_url: String,
+}
+
+impl PullRequest {
+    fn from_json(repo: &RepoId, json: PullRequestJson) -> Self {
+        PullRequest {
+            state: json.state,
+            repo: repo.clone(),
+            number: json.id,
+            web_url: json.links.html.href,
+        }
+    }
+
+    pub fn id(&self) -> PullRequestId {
+        PullRequestId {
+            repo: self.repo.clone(),
+            number: self.number,
+        }
+    }
+}
+
+/// A repository on Bitbucket, e.g. 'my-workspace/my-repo'.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RepoId {
+    pub workspace: String,
+    pub slug: String,
+}
+
+/// An id containing just enough data to uniquely identify a pull request on Bitbucket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PullRequestId {
+    pub repo: RepoId,
+    pub number: usize,
+}
+
+impl PullRequestId {
+    pub fn url(&self) -> String {
+        format!(
+            "https://bitbucket.org/{}/{}/pull-requests/{}",
+            self.repo.workspace, self.repo.slug, self.number
+        )
+    }
+}
+
+pub struct PullRequestOptions {
+    pub title: String,
+    pub description: String,
+    // Bitbucket keeps a draft from being merged until it is published.
+    pub draft: bool,
+}
+
+pub struct Bitbucket {
+    token: String,
+    client: reqwest::Client,
+}
+
+impl Bitbucket {
+    /// Uses the access token in BITBUCKET_TOKEN, which needs the 'pullrequest:write' scope.
+    pub fn new() -> Result<Self> {
+        let token = env::var("BITBUCKET_TOKEN")?;
+        Ok(Self {
+            client: reqwest::Client::new(),
+            token,
+        })
+    }
+
+    fn get(&self, endpoint: &str) -> reqwest::RequestBuilder {
+        self.client
+            .get(format!("{BITBUCKET_BASE_URL}/{endpoint}"))
+            .bearer_auth(&self.token)
+    }
+
+    fn post(&self, endpoint: &str) -> reqwest::RequestBuilder {
+        self.client
+            .post(format!("{BITBUCKET_BASE_URL}/{endpoint}"))
+            .bearer_auth(&self.token)
+    }
+
+    pub async fn get_pr(&self, pr_id: &PullRequestId) -> Result<PullRequest> {
+        let response = self
+            .get(&format!(
+                "repositories/{}/{}/pullrequests/{}",
+                pr_id.repo.workspace, pr_id.repo.slug, pr_id.number
+            ))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(PullRequest::from_json(&pr_id.repo, response.json().await?))
+    }
+
+    /// Opens a pull request from 'source_branch' in 'source' into 'target_branch' in 'target'.
+    pub async fn create_pr(
+        &self,
+        target: &RepoId,
+        source: &RepoId,
+        source_branch: &str,
+        target_branch: &str,
+        options: &PullRequestOptions,
+    ) -> Result<PullRequest> {
+        let body = json!({
+            "title": options.title,
+            "description": options.description,
+            "draft": options.draft,
+            "source": {
+                "branch": { "name": source_branch },
+                "repository": { "full_name": format!("{}/{}", source.workspace, source.slug) },
+            },
+            "destination": {
+                "branch": { "name": target_branch },
+            },
+        });
+        let response = self
+            .post(&format!(
+                "repositories/{}/{}/pullrequests",
+                target.workspace, target.slug
+            ))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(PullRequest::from_json(target, response.json().await?))
+    }
+}
@@ -0,0 +1,99 @@
+//! Shell completion scripts for 'g'. giti parses its arguments by hand, so the scripts are built
+//! from the static list of the subcommands that giti handles itself.
+use crate::error::{Error, Result};
+
+/// The subcommands that giti intercepts with a short description. Everything else goes to git.
+const COMMANDS: &[(&str, &str)] = &[
+    ("amend", "Amend the tip commit and force push it"),
+    ("branch", "Run git branch and keep the diffbase in sync"),
+    ("branches", "List branches by when they were created"),
+    ("checkout", "Check out a branch and update submodules"),
+    ("cleanup", "Delete branches whose PR is closed"),
+    ("clone", "Clone a repository"),
+    ("config", "Get, set or list the giti settings"),
+    ("diffbase", "Show, set or clear the diffbase"),
+    ("doctor", "Find and repair diffbase problems"),
+    ("down", "Check out the child branch"),
+    ("fix", "Run formatters on changed files"),
+    ("fixup", "Commit a fixup for a commit on the branch"),
+    ("init-stack", "Guess diffbases for existing branches"),
+    ("merge", "Merge and remember the diffbase"),
+    ("open", "Open the PR of the current branch"),
+    ("pr", "Open a pull request"),
+    ("prs", "List my pull requests"),
+    ("pullc", "Merge parents into all children"),
+    ("rebase", "Rebase onto the diffbase"),
+    ("review", "Check out a pull request for review"),
+    ("squash", "Squash the branch into one commit"),
+    ("stacklog", "Show the commits of the stack"),
+    ("start", "Start a new branch"),
+    ("sync", "Pull and push the diffbase tree"),
+    ("tree", "Show the diffbase tree"),
+    ("uncommitted", "Show changes against the diffbase"),
+    ("undo-cleanup", "Restore branches deleted by cleanup"),
+    ("unwip", "Undo g wip"),
+    ("up", "Check out the parent branch"),
+    ("wip", "Park changes and check out the parent"),
+];
+
+/// Returns the completion script for 'shell', which is one of 'bash', 'zsh' or 'fish'.
+pub fn script(shell: &str) -> Result<String> {
+    let names: Vec<&str> = COMMANDS.iter().map(|(name, _)| *name).collect();
+    match shell {
+        "bash" => Ok(format!(
+            "_g() {{\n    \
+                 if [ \"$COMP_CWORD\" -eq 1 ]; then\n        \
+                     COMPREPLY=($(compgen -W \"{}\" -- \"${{COMP_WORDS[1]}}\"))\n    \
+                 fi\n\
+             }}\n\
+             complete -o default -F _g g\n",
+            names.join(" ")
+        )),
+        "zsh" => {
+            let mut out =
+                String::from("#compdef g\n_g() {\n    local -a commands\n    commands=(\n");
+            for (name, description) in COMMANDS {
+                out.push_str(&format!("        '{}:{}'\n", name, description));
+            }
+            out.push_str(
+                "    )\n    if (( CURRENT == 2 )); then\n        \
+                     _describe 'command' commands\n    \
+                 else\n        \
+                     _files\n    \
+                 fi\n\
+                 }\n\
+                 compdef _g g\n",
+            );
+            Ok(out)
+        }
+        "fish" => Ok(COMMANDS
+            .iter()
+            .map(|(name, description)| {
+                format!(
+                    "complete -c g -n __fish_use_subcommand -a {} -d '{}'\n",
+                    name, description
+                )
+            })
+            .collect()),
+        _ => Err(Error::general(format!(
+            "Unknown shell {}. Supported are bash, zsh and fish.",
+            shell
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{script, COMMANDS};
+
+    #[test]
+    fn test_script() {
+        for shell in ["bash", "zsh", "fish"] {
+            let script = script(shell).unwrap();
+            for (name, _) in COMMANDS {
+                assert!(script.contains(name), "{} is missing for {}", name, shell);
+            }
+        }
+        assert!(script("tcsh").is_err());
+    }
+}
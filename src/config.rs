@@ -0,0 +1,222 @@
+//! Settings for giti. They are stored in git's configuration under the 'giti.' namespace, so the
+//! usual git precedence applies: the repository's config overrides the global one.
+use crate::error::{Error, Result};
+
+/// Opens the configuration of the repository we are in, or the global one outside of a repository.
+fn open() -> Result<git2::Config> {
+    match git2::Repository::discover(".") {
+        Ok(repo) => Ok(repo.config()?),
+        Err(_) => Ok(git2::Config::open_default()?),
+    }
+}
+
+/// Returns the value of 'key', if it is set.
+pub fn get_string(key: &str) -> Option<String> {
+    open().ok()?.get_string(key).ok()
+}
+
+/// Returns all values of the multi-valued 'key'. Every value can also be a comma separated list,
+/// so 'giti.foo = a,b' and two 'giti.foo' entries are equivalent. Only the values of the most
+/// specific config file that sets 'key' are used, so a repository can override a global list.
+pub fn get_list(key: &str) -> Vec<String> {
+    let mut rv = Vec::new();
+    let config = match open() {
+        Ok(c) => c,
+        Err(_) => return rv,
+    };
+    let mut entries = match config.multivar(key, None) {
+        Ok(e) => e,
+        Err(_) => return rv,
+    };
+    let mut level = None;
+    while let Some(Ok(entry)) = entries.next() {
+        let entry_level = entry.level() as i32;
+        if level.is_some_and(|l| l > entry_level) {
+            continue;
+        }
+        if level.is_some_and(|l| l < entry_level) {
+            rv.clear();
+        }
+        level = Some(entry_level);
+        if let Some(value) = entry.value() {
+            rv.extend(split_list(value));
+        }
+    }
+    rv
+}
+
+/// Splits a list value like 'a, b' or '["a", "b"]' into its items.
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .trim()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split(',')
+        .map(|s| s.trim().trim_matches('"').to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// The settings giti knows about, with a short description for 'g config list'.
+const SETTINGS: &[(&str, &str)] = &[
+    (
+        "giti.mainBranch",
+        "The main branch, if origin/HEAD is not set.",
+    ),
+    (
+        "giti.branchTemplate",
+        "The name of branches for 'g start --issue', using {issue} and {name}.",
+    ),
+    (
+        "giti.cleanup.keep",
+        "Globs of branches that 'g cleanup' keeps.",
+    ),
+    (
+        "giti.cleanup.protect",
+        "Branches that 'g cleanup' never deletes.",
+    ),
+    ("giti.github.host", "The host of GitHub Enterprise."),
+    (
+        "giti.github.hosts",
+        "Further host names, e.g. ssh aliases, that are GitHub.",
+    ),
+    (
+        "giti.github.tokenKind",
+        "'personal' or 'installation', the kind of GITHUB_TOKEN.",
+    ),
+    ("giti.gitlab.host", "The host of a self-hosted GitLab."),
+    (
+        "giti.gitlab.hosts",
+        "Further host names, e.g. ssh aliases, that are GitLab.",
+    ),
+    (
+        "giti.bitbucket.hosts",
+        "Further host names, e.g. ssh aliases, that are Bitbucket.",
+    ),
+    ("giti.pr.reviewers", "Reviewers to request for every PR."),
+    ("giti.pr.readyAddLabels", "Labels that 'g pr --ready' adds."),
+    (
+        "giti.pr.readyRemoveLabels",
+        "Labels that 'g pr --ready' removes.",
+    ),
+    (
+        "giti.pr.titlePattern",
+        "A regex that the titles of new PRs must match.",
+    ),
+    (
+        "giti.pr.titleAttempts",
+        "How often the editor is opened for a title that does not match.",
+    ),
+    (
+        "giti.pr.maxStackDepth",
+        "Warn about PRs on branches stacked deeper than this.",
+    ),
+];
+
+/// Returns true if 'args' ('config' and its arguments) are for 'g config' and not for
+/// 'git config', i.e. they list the giti settings or get or set one of them.
+pub fn is_giti_config(args: &[&str]) -> bool {
+    let args: Vec<&str> = args[1..]
+        .iter()
+        .copied()
+        .filter(|a| *a != "--global")
+        .collect();
+    match args.as_slice() {
+        ["list"] => true,
+        ["get", key] | ["set", key, _] => key.starts_with("giti."),
+        _ => false,
+    }
+}
+
+/// Gets, sets or lists the giti settings. Settings are written to the repository's config, or with
+/// '--global' to the global one.
+pub fn handle_config(args: &[&str]) -> Result<()> {
+    let global = args.contains(&"--global");
+    let args: Vec<&str> = args[1..]
+        .iter()
+        .copied()
+        .filter(|a| *a != "--global")
+        .collect();
+    match args.as_slice() {
+        ["list"] => {
+            let config = open()?;
+            for (key, description) in SETTINGS {
+                let values = values(&config, key);
+                if values.is_empty() {
+                    println!("{}", key);
+                } else {
+                    println!("{} = {}", key, values.join(", "));
+                }
+                println!("    {}", description);
+            }
+            Ok(())
+        }
+        ["get", key] => {
+            let values = values(&open()?, key);
+            if values.is_empty() {
+                return Err(Error::general(format!("{} is not set.", key)));
+            }
+            for value in values {
+                println!("{}", value);
+            }
+            Ok(())
+        }
+        ["set", key, value] => {
+            if !SETTINGS.iter().any(|(k, _)| k == key) {
+                return Err(Error::general(format!(
+                    "{} is no giti setting. Run 'g config list' to see them all.",
+                    key
+                )));
+            }
+            let mut config = if global {
+                git2::Config::open_default()?.open_level(git2::ConfigLevel::Global)?
+            } else {
+                let repo = git2::Repository::discover(".").map_err(|_| {
+                    Error::general(
+                        "Not in a repository. Use --global to change the global config."
+                            .to_string(),
+                    )
+                })?;
+                repo.config()?.open_level(git2::ConfigLevel::Local)?
+            };
+            // Lists can have several entries, which would all be replaced by this one.
+            let _ = config.remove_multivar(key, ".*");
+            config.set_str(key, value)?;
+            Ok(())
+        }
+        _ => unreachable!("Other arguments are handed to 'git config'."),
+    }
+}
+
+/// Returns all values of 'key' in 'config'.
+fn values(config: &git2::Config, key: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    if let Ok(mut entries) = config.multivar(key, None) {
+        while let Some(Ok(entry)) = entries.next() {
+            values.extend(entry.value().map(|v| v.to_string()));
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_giti_config;
+
+    #[test]
+    fn test_is_giti_config() {
+        assert!(is_giti_config(&["config", "list"]));
+        assert!(is_giti_config(&["config", "get", "giti.mainBranch"]));
+        assert!(is_giti_config(&[
+            "config",
+            "--global",
+            "set",
+            "giti.mainBranch",
+            "trunk"
+        ]));
+        assert!(!is_giti_config(&["config", "get", "user.name"]));
+        assert!(!is_giti_config(&["config", "--list"]));
+        assert!(!is_giti_config(&["config", "user.name", "Me"]));
+        assert!(!is_giti_config(&["config", "list", "--show-origin"]));
+    }
+}
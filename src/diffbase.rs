@@ -1,16 +1,21 @@
-use crate::dispatch::{dispatch_to, run_command};
+use crate::bitbucket;
+use crate::dispatch::{communicate_checked, dispatch_to, run_command};
 use crate::error::{Error, ErrorKind, Result};
 use crate::git;
 use crate::github;
 use crate::gitlab;
+use crate::prompt;
+use chrono::{DateTime, FixedOffset, Local, TimeZone};
+use fs2::FileExt;
 use getopts;
 use git2;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::fs;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, IsTerminal, Read};
 use std::path;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -18,6 +23,27 @@ use std::path;
 pub enum MergeRequest {
     GitHub(github::PullRequestId),
     GitLab(gitlab::PullRequestId),
+    Bitbucket(bitbucket::PullRequestId),
+}
+
+impl MergeRequest {
+    pub fn url(&self) -> String {
+        match self {
+            MergeRequest::GitHub(pr_id) => pr_id.url(),
+            MergeRequest::GitLab(mr_id) => mr_id.url.clone(),
+            MergeRequest::Bitbucket(pr_id) => pr_id.url(),
+        }
+    }
+}
+
+impl fmt::Display for MergeRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeRequest::GitHub(pr_id) => write!(f, "{}", pr_id),
+            MergeRequest::GitLab(mr_id) => write!(f, "{}", mr_id.url),
+            MergeRequest::Bitbucket(pr_id) => write!(f, "{}", pr_id.url()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -25,6 +51,12 @@ pub struct DiffbaseJson {
     branch: String,
     diffbase: Option<String>,
     merge_request: Option<MergeRequest>,
+    // RFC 3339 timestamp of when the branch was first seen. Older databases do not have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<String>,
+    // The issue the branch works on, see 'g start --issue'.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    issue: Option<i64>,
 }
 
 #[derive(Debug, Default)]
@@ -32,29 +64,83 @@ struct DiffbaseEntry {
     parent: Option<String>,
     children: Vec<String>,
     merge_request: Option<MergeRequest>,
+    created_at: Option<String>,
+    issue: Option<i64>,
+}
+
+impl DiffbaseEntry {
+    /// An entry for a branch that the diffbase sees for the first time.
+    fn new() -> Self {
+        DiffbaseEntry {
+            created_at: Some(Local::now().to_rfc3339()),
+            ..Default::default()
+        }
+    }
 }
 
 pub struct Diffbase {
     entries: HashMap<String, DiffbaseEntry>,
     json_file_path: path::PathBuf,
+    // Problems with diffbase.json that were worked around while loading it.
+    load_problems: Vec<String>,
+    // Held while a command that changes the diffbase runs, so that concurrent 'g' processes do not
+    // overwrite each other's changes. The lock is released when this is dropped.
+    _lock: Option<File>,
+}
+
+/// Returns the git directory that all worktrees of a repository share. For a linked worktree,
+/// 'git_dir' is '<shared git dir>/worktrees/<name>/', otherwise it is the shared one already.
+fn shared_git_dir(git_dir: &path::Path) -> path::PathBuf {
+    match git_dir.parent() {
+        Some(worktrees) if worktrees.file_name().is_some_and(|n| n == "worktrees") => {
+            worktrees.parent().unwrap_or(worktrees).to_path_buf()
+        }
+        _ => git_dir.to_path_buf(),
+    }
 }
 
 impl Diffbase {
+    /// Loads the diffbase for reading only. Changes to it must not be written to disk.
     pub fn new(repo: &git2::Repository) -> Result<Diffbase> {
+        Self::load(repo, None)
+    }
+
+    /// Loads the diffbase for a command that changes it. Blocks until no other 'g' process holds
+    /// the diffbase lock of this repository, and says so if it has to wait.
+    pub fn new_locked(repo: &git2::Repository) -> Result<Diffbase> {
+        let lock = File::create(shared_git_dir(repo.path()).join("diffbase.json.lock"))?;
+        if lock.try_lock_exclusive().is_err() {
+            eprintln!("Waiting for another g process to release the diffbase lock…");
+            lock.lock_exclusive()?;
+        }
+        Self::load(repo, Some(lock))
+    }
+
+    /// Writes the diffbase back without what could not be loaded, e.g. branches that were deleted
+    /// with 'git branch -D', so that it is only reported once.
+    pub fn prune(repo: &git2::Repository) -> Result<()> {
+        Self::new_locked(repo)?.write_to_disk()
+    }
+
+    /// Returns true if diffbase.json had problems that were worked around while loading it.
+    pub fn has_load_problems(&self) -> bool {
+        !self.load_problems.is_empty()
+    }
+
+    fn load(repo: &git2::Repository, lock: Option<File>) -> Result<Diffbase> {
         let mut diffbase = Diffbase {
             entries: HashMap::<String, DiffbaseEntry>::new(),
-            json_file_path: repo.path().join("diffbase.json"),
+            json_file_path: shared_git_dir(repo.path()).join("diffbase.json"),
+            load_problems: Vec::new(),
+            _lock: lock,
         };
 
         for branch in git::get_all_local_branch_names(repo)? {
-            diffbase.entries.insert(
-                branch.to_string(),
-                DiffbaseEntry {
-                    children: Vec::new(),
-                    parent: None,
-                    merge_request: None,
-                },
-            );
+            let entry = DiffbaseEntry {
+                created_at: branch_created_at(repo, &branch),
+                ..Default::default()
+            };
+            diffbase.entries.insert(branch.to_string(), entry);
         }
 
         if fs::metadata(&diffbase.json_file_path).is_err() {
@@ -69,45 +155,73 @@ impl Diffbase {
 
         for entry in diffbase_json {
             if !diffbase.entries.contains_key(&entry.branch) {
-                println!(
-                    "Branch {} no longer exists. Removing it from the diffbase map.",
-                    entry.branch
-                );
+                // Only reported when the removal is written back, i.e. once.
+                if diffbase._lock.is_some() {
+                    eprintln!(
+                        "Branch {} no longer exists. Removing it from the diffbase map.",
+                        entry.branch
+                    );
+                }
+                diffbase
+                    .load_problems
+                    .push(format!("Branch {} no longer exists.", entry.branch));
                 continue;
             }
 
-            diffbase
-                .entries
-                .get_mut(&entry.branch)
-                .unwrap()
-                .merge_request = entry.merge_request;
+            let stored = diffbase.entries.get_mut(&entry.branch).unwrap();
+            stored.merge_request = entry.merge_request;
+            if entry.created_at.is_some() {
+                stored.created_at = entry.created_at;
+            }
+            stored.issue = entry.issue;
 
             let parent_name = match entry.diffbase {
                 None => continue,
                 Some(ref s) => s,
             };
             if !diffbase.entries.contains_key(parent_name) {
+                diffbase.load_problems.push(format!(
+                    "The diffbase {} of {} no longer exists.",
+                    parent_name, entry.branch
+                ));
                 continue;
             }
 
-            diffbase
-                .set_diffbase_quiet(&entry.branch, parent_name)
-                .expect("Could not set diffbase.");
+            if let Err(err) = diffbase.set_diffbase_quiet(&entry.branch, parent_name) {
+                if err.kind != ErrorKind::DiffbaseCycle {
+                    return Err(err);
+                }
+                if diffbase._lock.is_some() {
+                    eprintln!("Ignoring the stored diffbase of {}: {}", entry.branch, err);
+                }
+                diffbase.load_problems.push(err.to_string());
+            }
         }
         Ok(diffbase)
     }
 
     fn set_diffbase_quiet(&mut self, branch: &str, diffbase: &str) -> Result<()> {
-        let main_branch = git::get_main_branch();
+        let mut ancestor = Some(diffbase);
+        while let Some(a) = ancestor {
+            if a == branch {
+                return Err(Error::diffbase_cycle(branch, diffbase));
+            }
+            ancestor = self.get_parent(a);
+        }
+
+        let main_branch = git::get_main_branch()?;
         if diffbase == main_branch || diffbase.starts_with("origin/") {
             return Err(Error::branch_cant_be_diffbase(diffbase));
         }
+        // A branch can only have one parent.
+        self.clear_diffbase(branch);
         if !self.entries.contains_key(branch) {
-            self.entries.insert(branch.to_string(), Default::default());
+            self.entries
+                .insert(branch.to_string(), DiffbaseEntry::new());
         }
         if !self.entries.contains_key(diffbase) {
             self.entries
-                .insert(diffbase.to_string(), Default::default());
+                .insert(diffbase.to_string(), DiffbaseEntry::new());
         }
         self.entries.get_mut(branch).unwrap().parent = Some(diffbase.to_string());
         self.entries
@@ -124,6 +238,16 @@ impl Diffbase {
         Ok(())
     }
 
+    /// Makes 'branch' a root by unlinking it from its parent. Its children stay with it. Returns
+    /// the old parent, if there was one.
+    pub fn clear_diffbase(&mut self, branch: &str) -> Option<String> {
+        let parent = self.entries.get_mut(branch)?.parent.take()?;
+        if let Some(entry) = self.entries.get_mut(&parent) {
+            entry.children.retain(|c| c != branch);
+        }
+        Some(parent)
+    }
+
     pub fn write_to_disk(&self) -> Result<()> {
         let mut json_entries = Vec::new();
         for (key, entry) in &self.entries {
@@ -131,13 +255,20 @@ impl Diffbase {
                 branch: key.to_string(),
                 diffbase: entry.parent.clone(),
                 merge_request: entry.merge_request.clone(),
+                created_at: entry.created_at.clone(),
+                issue: entry.issue,
             });
         }
         let json_string = serde_json::to_string_pretty(&json_entries)?;
 
-        File::create(&self.json_file_path)
-            .and_then(|mut file| write!(file, "{}", &json_string))
-            .map_err(Error::from)
+        // Written next to the real file and moved over it, so that an interrupted 'g' never leaves
+        // a truncated diffbase behind.
+        let tmp_path = self
+            .json_file_path
+            .with_extension(format!("json.{}.tmp", std::process::id()));
+        fs::write(&tmp_path, json_string)?;
+        fs::rename(&tmp_path, &self.json_file_path)?;
+        Ok(())
     }
 
     /// Renames the branch 'current' to 'new'.
@@ -158,6 +289,121 @@ impl Diffbase {
         }
     }
 
+    /// Returns all inconsistencies: those that were found while loading diffbase.json and those in
+    /// the branches' links to each other.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = self.load_problems.clone();
+        let mut branches: Vec<&String> = self.entries.keys().collect();
+        branches.sort();
+        for branch in branches {
+            let entry = &self.entries[branch];
+            if let Some(parent) = &entry.parent {
+                match self.entries.get(parent) {
+                    None => problems.push(format!(
+                        "The diffbase {} of {} does not exist.",
+                        parent, branch
+                    )),
+                    Some(p) if !p.children.contains(branch) => problems.push(format!(
+                        "{} has the diffbase {}, but is not one of its children.",
+                        branch, parent
+                    )),
+                    Some(_) => (),
+                }
+            }
+            for child in &entry.children {
+                match self.entries.get(child) {
+                    None => {
+                        problems.push(format!("The child {} of {} does not exist.", child, branch))
+                    }
+                    Some(c) if c.parent.as_ref() != Some(branch) => problems.push(format!(
+                        "{} lists {} as a child, but its diffbase is {}.",
+                        branch,
+                        child,
+                        c.parent.as_deref().unwrap_or("not set")
+                    )),
+                    Some(_) => (),
+                }
+            }
+            if self.cycle_start(branch).as_deref() == Some(branch) {
+                problems.push(format!("{} is its own ancestor.", branch));
+            }
+        }
+        problems
+    }
+
+    /// Returns the first branch that shows up twice when walking up from 'branch', if any.
+    fn cycle_start(&self, branch: &str) -> Option<String> {
+        let mut seen = BTreeSet::new();
+        let mut current = Some(branch);
+        while let Some(c) = current {
+            if !seen.insert(c) {
+                return Some(c.to_string());
+            }
+            current = self.get_parent(c);
+        }
+        None
+    }
+
+    /// Repairs what 'validate' complains about: dangling diffbases are dropped, cycles are broken
+    /// up and the children are rebuilt from the diffbases.
+    pub fn repair(&mut self) {
+        let dangling: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| {
+                e.parent
+                    .as_ref()
+                    .is_some_and(|p| !self.entries.contains_key(p))
+            })
+            .map(|(b, _)| b.clone())
+            .collect();
+        for branch in dangling {
+            self.entries.get_mut(&branch).unwrap().parent = None;
+        }
+
+        let mut branches: Vec<String> = self.entries.keys().cloned().collect();
+        branches.sort();
+        for branch in &branches {
+            if let Some(start) = self.cycle_start(branch) {
+                self.entries.get_mut(&start).unwrap().parent = None;
+            }
+        }
+
+        for entry in self.entries.values_mut() {
+            entry.children.clear();
+        }
+        for branch in &branches {
+            if let Some(parent) = self.entries[branch].parent.clone() {
+                self.entries
+                    .get_mut(&parent)
+                    .unwrap()
+                    .children
+                    .push(branch.clone());
+            }
+        }
+        self.load_problems.clear();
+    }
+
+    /// Forgets the branch 'branch'. Its children move up to its parent, or become roots if it had
+    /// none.
+    pub fn remove(&mut self, branch: &str) {
+        let entry = match self.entries.remove(branch) {
+            None => return,
+            Some(e) => e,
+        };
+        for child in &entry.children {
+            if let Some(c) = self.entries.get_mut(child) {
+                c.parent = entry.parent.clone();
+            }
+        }
+        for val in self.entries.values_mut() {
+            val.children.retain(|c| c != branch);
+        }
+        if let Some(parent) = entry.parent.as_ref().and_then(|p| self.entries.get_mut(p)) {
+            parent.children.extend(entry.children);
+        }
+    }
+
     /// Returns the name of the parent branch.
     pub fn get_parent(&self, branch: &str) -> Option<&str> {
         if let Some(entry) = self.entries.get(branch) {
@@ -170,14 +416,50 @@ impl Diffbase {
 
     /// Returns all children. Returns none if 'branch' is not in the diffbase list.
     pub fn get_children(&self, branch: &str) -> Option<Vec<&str>> {
-        let entry = match self.entries.get(branch) {
-            None => return None,
-            Some(e) => e,
-        };
-
+        let entry = self.entries.get(branch)?;
         Some(entry.children.iter().map(|s| s as &str).collect())
     }
 
+    /// Returns all branches below 'branch' in depth-first order, i.e. every branch comes right
+    /// before its children. Returns an empty list if 'branch' has no children.
+    pub fn get_descendants<'a>(&'a self, branch: &'a str) -> Vec<&'a str> {
+        let mut descendants = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut todo = vec![branch];
+        while let Some(current) = todo.pop() {
+            if !seen.insert(current) {
+                continue;
+            }
+            if current != branch {
+                descendants.push(current);
+            }
+            let children = self.get_children(current).unwrap_or_default();
+            todo.extend(children.into_iter().rev());
+        }
+        descendants
+    }
+
+    /// Returns 'branch' and its parents up to, but excluding, its root. The list is empty if
+    /// 'branch' is a root itself.
+    pub fn get_ancestors<'a>(&'a self, branch: &'a str) -> Vec<&'a str> {
+        let mut ancestors = Vec::new();
+        let mut branch = branch;
+        while let Some(parent) = self.get_parent(branch) {
+            // Guards against cycles, which 'load' should not let in.
+            if ancestors.contains(&branch) {
+                break;
+            }
+            ancestors.push(branch);
+            branch = parent;
+        }
+        ancestors
+    }
+
+    /// Returns how many diffbases are between 'branch' and its root. A root has depth 0.
+    pub fn depth(&self, branch: &str) -> usize {
+        self.get_ancestors(branch).len()
+    }
+
     /// Returns the ancestor of 'branch'. Might be the branch itself. Returns None if 'branch' is
     /// not a valid branch name.
     pub fn get_root<'a>(&'a self, branch: &'a str) -> Option<&'a str> {
@@ -195,6 +477,80 @@ impl Diffbase {
         }
     }
 
+    /// Draws all branches as trees below their roots, e.g.
+    ///
+    /// ```text
+    /// a
+    /// ├── b (SirVer/giti#12)
+    /// │   └── c <-
+    /// └── d
+    /// ```
+    ///
+    /// The current branch is marked with an arrow. A branch that shows up again because of a cycle
+    /// is marked as such and not descended into.
+    fn render_tree(&self, current_branch: &str) -> String {
+        let mut out = String::new();
+        let mut seen = BTreeSet::new();
+        let mut roots: Vec<&str> = self
+            .entries
+            .keys()
+            .filter(|b| self.get_parent(b).is_none())
+            .map(|b| b as &str)
+            .collect();
+        roots.sort_unstable();
+        // Branches in a cycle have no root, so they are drawn starting at the first one of them.
+        let mut all: Vec<&str> = self.entries.keys().map(|b| b as &str).collect();
+        all.sort_unstable();
+        for branch in roots.into_iter().chain(all) {
+            if !seen.contains(branch) {
+                self.render_subtree(branch, current_branch, "", "", &mut seen, &mut out);
+            }
+        }
+        out
+    }
+
+    fn render_subtree<'a>(
+        &'a self,
+        branch: &'a str,
+        current_branch: &str,
+        prefix: &str,
+        child_prefix: &str,
+        seen: &mut BTreeSet<&'a str>,
+        out: &mut String,
+    ) {
+        out.push_str(prefix);
+        out.push_str(branch);
+        if !seen.insert(branch) {
+            out.push_str(" (cycle)\n");
+            return;
+        }
+        if let Some(merge_request) = self.get_merge_request(branch) {
+            out.push_str(&format!(" ({})", merge_request));
+        }
+        if branch == current_branch {
+            out.push_str(" <-");
+        }
+        out.push('\n');
+
+        let mut children = self.get_children(branch).unwrap_or_default();
+        children.sort_unstable();
+        for (i, child) in children.iter().enumerate() {
+            let (prefix, next) = if i + 1 == children.len() {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            self.render_subtree(
+                child,
+                current_branch,
+                &format!("{}{}", child_prefix, prefix),
+                &format!("{}{}", child_prefix, next),
+                seen,
+                out,
+            );
+        }
+    }
+
     pub fn get_merge_request(&self, branch: &str) -> Option<&MergeRequest> {
         self.entries
             .get(branch)
@@ -203,10 +559,56 @@ impl Diffbase {
 
     pub fn set_merge_request(&mut self, branch: &str, merge_request: MergeRequest) {
         if !self.entries.contains_key(branch) {
-            self.entries.insert(branch.to_string(), Default::default());
+            self.entries
+                .insert(branch.to_string(), DiffbaseEntry::new());
         }
         self.entries.get_mut(branch).unwrap().merge_request = Some(merge_request);
     }
+
+    /// Returns the issue that 'branch' works on, if it was started with one.
+    pub fn get_issue(&self, branch: &str) -> Option<i64> {
+        self.entries.get(branch).and_then(|b| b.issue)
+    }
+
+    pub fn set_issue(&mut self, branch: &str, issue: i64) {
+        if !self.entries.contains_key(branch) {
+            self.entries
+                .insert(branch.to_string(), DiffbaseEntry::new());
+        }
+        self.entries.get_mut(branch).unwrap().issue = Some(issue);
+    }
+
+    /// Returns all branches with the time they were created, oldest first. Branches whose creation
+    /// time is unknown come last.
+    pub fn get_branches_by_age(&self) -> Vec<(&str, Option<DateTime<FixedOffset>>)> {
+        let mut branches: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(branch, entry)| {
+                let created_at = entry
+                    .created_at
+                    .as_ref()
+                    .and_then(|c| DateTime::parse_from_rfc3339(c).ok());
+                (branch as &str, created_at)
+            })
+            .collect();
+        branches.sort_by_key(|(branch, created_at)| (created_at.is_none(), *created_at, *branch));
+        branches
+    }
+}
+
+/// Returns when 'branch' was created as RFC 3339 timestamp, if its reflog still goes back that far.
+fn branch_created_at(repo: &git2::Repository, branch: &str) -> Option<String> {
+    let reflog = repo.reflog(&format!("refs/heads/{}", branch)).ok()?;
+    // The oldest entry comes last. It only created the branch if there was nothing before.
+    let oldest = reflog.iter().next_back()?;
+    if !oldest.id_old().is_zero() {
+        return None;
+    }
+    let when = oldest.committer().when();
+    let offset = FixedOffset::east_opt(when.offset_minutes() * 60)?;
+    let created_at = offset.timestamp_opt(when.seconds(), 0).single()?;
+    Some(created_at.to_rfc3339())
 }
 
 /// Intercepts --diffbase argument and sets diffbase accordingly.
@@ -225,37 +627,258 @@ pub fn handle_merge(args: &[&str], repo: &git2::Repository, diffbase: &mut Diffb
     dispatch_to("git", args)
 }
 
+/// Rebases the current branch onto its diffbase for 'g rebase' or onto the root of its stack for
+/// 'g rebase --onto-root'. Everything else is passed on to git.
+pub fn handle_rebase(args: &[&str], repo: &git2::Repository, diffbase: &Diffbase) -> Result<()> {
+    let onto_root = match &args[1..] {
+        [] => false,
+        ["--onto-root"] => true,
+        _ => return dispatch_to("git", args),
+    };
+
+    let current_branch = git::get_current_branch(repo);
+    let onto = if onto_root {
+        diffbase
+            .get_root(&current_branch)
+            .filter(|root| *root != current_branch)
+    } else {
+        diffbase.get_parent(&current_branch)
+    };
+    let onto = match onto {
+        Some(onto) => onto,
+        None => {
+            return Err(Error::general(format!(
+                "{} has no diffbase.",
+                current_branch
+            )))
+        }
+    };
+
+    git::expect_working_directory_clean()?;
+    if let Err(err) = run_command(&["git", "rebase", onto]) {
+        // git might have failed before starting the rebase, then there is nothing to abort.
+        let in_progress = matches!(
+            repo.state(),
+            git2::RepositoryState::Rebase
+                | git2::RepositoryState::RebaseInteractive
+                | git2::RepositoryState::RebaseMerge
+                | git2::RepositoryState::ApplyMailboxOrRebase
+        );
+        if err.kind != ErrorKind::SubcommandFailed || !in_progress {
+            return Err(err);
+        }
+        run_command(&["git", "rebase", "--abort"])?;
+        return Err(Error::general(format!(
+            "Rebasing {} onto {} ran into conflicts and was aborted.",
+            current_branch, onto
+        )));
+    }
+    Ok(())
+}
+
 /// Intercepts checkout -b branch to set the diffbase on branching.
 pub fn handle_checkout(
     args: &[&str],
     repo: &git2::Repository,
     diffbase: &mut Diffbase,
 ) -> Result<()> {
+    // 'extract_option' would also match git's '--progress'.
+    let pr_number = args[1..].iter().enumerate().find_map(|(i, a)| match *a {
+        "--pr" => Some(args.get(i + 2).copied().unwrap_or_default()),
+        a => a.strip_prefix("--pr="),
+    });
+    if let Some(pr_number) = pr_number {
+        let number = pr_number.parse::<u64>().map_err(|_| match pr_number {
+            "" => Error::general("--pr needs a PR number.".to_string()),
+            _ => Error::general(format!("--pr needs a PR number, not {}.", pr_number)),
+        })?;
+        return git::checkout_pr(repo, number);
+    }
     let (new_branch_name, ignored, positional) = extract_option(Some("-b"), &args[1..]);
 
     if let Some(new_branch_name) = new_branch_name {
-        if let Err(err) = diffbase.set_diffbase(new_branch_name, &git::get_current_branch(repo)) {
-            if err.kind != ErrorKind::BranchCantBeDiffbase {
-                return Err(err);
+        let current_branch = git::get_current_branch(repo);
+        let local_branches = git::get_all_local_branch_names(repo)?;
+        let parent =
+            new_branch_diffbase(&positional, &current_branch, |b| local_branches.contains(b));
+        if let Some(parent) = parent {
+            if let Err(err) = diffbase.set_diffbase(new_branch_name, parent) {
+                if err.kind != ErrorKind::BranchCantBeDiffbase {
+                    return Err(err);
+                }
             }
         }
     }
 
-    if ignored.is_empty() && positional.len() == 1 {
-        git::checkout(repo, positional[0])?;
+    match checkout_route(new_branch_name, &ignored, &positional) {
+        CheckoutRoute::Branch(branch) => git::checkout(repo, branch),
+        CheckoutRoute::Previous => match previous_branch(repo) {
+            Some(branch) => git::checkout(repo, &branch),
+            // The previous checkout was not a branch, so there is nothing for us to do.
+            None => checkout_passthrough(args, repo),
+        },
+        CheckoutRoute::Passthrough => checkout_passthrough(args, repo),
+    }
+}
+
+/// Hands 'args' to git checkout unchanged. Submodules are only updated if HEAD moved, checking out
+/// paths, e.g. 'checkout <rev> -- <path>', leaves them alone.
+fn checkout_passthrough(args: &[&str], repo: &git2::Repository) -> Result<()> {
+    let head = |repo: &git2::Repository| repo.head().ok().and_then(|h| h.target());
+    let head_before = head(repo);
+    dispatch_to("git", args)?;
+    if head(repo) != head_before {
+        git::update_submodules(repo)?;
+    }
+    Ok(())
+}
+
+/// How 'g checkout' runs a checkout.
+#[derive(Debug, PartialEq, Eq)]
+enum CheckoutRoute<'a> {
+    /// A plain 'checkout <branch>', which is run by git::checkout.
+    Branch(&'a str),
+    /// 'checkout -', which goes back to the branch that was checked out before.
+    Previous,
+    /// Everything else, e.g. '-b', '--detach' or paths, is handed to git unchanged. Submodules are
+    /// updated if HEAD moved.
+    Passthrough,
+}
+
+fn checkout_route<'a>(
+    new_branch_name: Option<&str>,
+    ignored: &[&str],
+    positional: &[&'a str],
+) -> CheckoutRoute<'a> {
+    match (new_branch_name, ignored, positional) {
+        (None, [], [branch]) => CheckoutRoute::Branch(branch),
+        (None, ["-"], []) => CheckoutRoute::Previous,
+        _ => CheckoutRoute::Passthrough,
+    }
+}
+
+/// Returns the name of the branch that was checked out before the current one, if that was a
+/// branch and not a detached HEAD.
+fn previous_branch(repo: &git2::Repository) -> Option<String> {
+    let (_, reference) = repo.revparse_ext("@{-1}").ok()?;
+    let reference = reference?;
+    if !reference.is_branch() {
+        return None;
+    }
+    reference.shorthand().map(|s| s.to_string())
+}
+
+/// Returns the diffbase of the branch created by 'checkout -b <new> [<start-point>]'. That is the
+/// start-point if one is given and the current branch otherwise. Returns None if the start-point is
+/// not a local branch, e.g. 'origin/main' or a commit.
+fn new_branch_diffbase<'a>(
+    positional: &[&'a str],
+    current_branch: &'a str,
+    is_local_branch: impl Fn(&str) -> bool,
+) -> Option<&'a str> {
+    match positional.first() {
+        None => Some(current_branch),
+        Some(start_point) if is_local_branch(start_point) => Some(start_point),
+        Some(_) => None,
+    }
+}
+
+/// Prints the diffbase hierarchy of all branches.
+pub fn handle_tree(repo: &git2::Repository, diffbase: &Diffbase) -> Result<()> {
+    print!("{}", diffbase.render_tree(&git::get_current_branch(repo)));
+    Ok(())
+}
+
+/// Reports inconsistencies in the diffbase database and repairs them with '--fix'.
+pub fn handle_doctor(args: &[&str], diffbase: &mut Diffbase) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optflag("", "fix", "Repair the problems that were found.");
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(err) => {
+            let brief = format!("{}\nUsage: g doctor [options]", err);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+    };
+
+    let problems = diffbase.validate();
+    if problems.is_empty() {
+        println!("The diffbase is consistent.");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("  {}", problem);
+    }
+    if matches.opt_present("fix") {
+        diffbase.repair();
+        println!("Repaired {} problems.", problems.len());
     } else {
-        dispatch_to("git", args)?;
+        println!("Run 'g doctor --fix' to repair them.");
+    }
+    Ok(())
+}
+
+/// Lists all branches by when they were first seen, oldest first.
+pub fn handle_branches(diffbase: &Diffbase) -> Result<()> {
+    let now = Local::now();
+    for (branch, created_at) in diffbase.get_branches_by_age() {
+        match created_at {
+            Some(c) => println!(
+                "{}  {:>4} days  {}",
+                c.format("%Y-%m-%d"),
+                now.signed_duration_since(c).num_days(),
+                branch
+            ),
+            None => println!("{:10}  {:>9}  {}", "unknown", "", branch),
+        }
+    }
+    Ok(())
+}
+
+/// Shows the commits of the stack from the root to the current branch, grouped by the branch that
+/// introduced them.
+pub fn handle_stacklog(repo: &git2::Repository, diffbase: &Diffbase) -> Result<()> {
+    let current_branch = git::get_current_branch(repo);
+    let ancestors = diffbase.get_ancestors(&current_branch);
+    if ancestors.is_empty() {
+        return Err(Error::general(format!(
+            "{} has no diffbase.",
+            current_branch
+        )));
+    }
+    for branch in ancestors.into_iter().rev() {
+        let parent = diffbase.get_parent(branch).unwrap();
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(repo.revparse_single(branch)?.id())?;
+        revwalk.hide(repo.revparse_single(parent)?.id())?;
+
+        println!("{} (on {}):", branch, parent);
+        for oid in revwalk {
+            let commit = repo.find_commit(oid?)?;
+            println!(
+                "  {} {}",
+                commit.as_object().short_id()?.as_str().unwrap_or_default(),
+                commit.summary().unwrap_or_default()
+            );
+        }
     }
     Ok(())
 }
 
-/// Interjects git branch -m to catch on renames.
+/// Interjects git branch -m to catch on renames. 'g branch --move-base' is handled by giti.
 pub fn handle_branch(
     args: &[&str],
     repo: &git2::Repository,
     diffbase: &mut Diffbase,
 ) -> Result<()> {
-    let (new_branch_name, _, _) = extract_option(Some("-m"), &args[1..]);
+    if args[1..]
+        .iter()
+        .any(|a| *a == "--move-base" || a.starts_with("--move-base="))
+    {
+        return handle_move_base(args, repo, diffbase);
+    }
+
+    let (new_branch_name, options, positional_args) = extract_option(Some("-m"), &args[1..]);
 
     if let Some(new_branch_name) = new_branch_name {
         let current_branch = git::get_current_branch(repo);
@@ -265,13 +888,141 @@ pub fn handle_branch(
         );
         diffbase.rename(&current_branch, new_branch_name);
     }
-    dispatch_to("git", args)
+
+    let deletes = options
+        .iter()
+        .any(|o| matches!(*o, "-d" | "-D" | "--delete"));
+    let result = dispatch_to("git", args);
+    if deletes {
+        // git might have refused to delete some of the branches, e.g. because they are unmerged.
+        for branch in positional_args {
+            if repo.find_branch(branch, git2::BranchType::Local).is_err() {
+                diffbase.remove(branch);
+            }
+        }
+    }
+    result
+}
+
+/// Moves the current branch with its children to a new parent in the diffbase tree.
+fn handle_move_base(args: &[&str], repo: &git2::Repository, diffbase: &mut Diffbase) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.reqopt(
+        "",
+        "move-base",
+        "Make this branch the new diffbase of the current branch.",
+        "PARENT",
+    );
+    opts.optflag(
+        "r",
+        "rebase",
+        "Rebase the commits of the branch onto the new diffbase.",
+    );
+    let usage = "Usage: g branch --move-base PARENT [options]";
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) if !m.free.is_empty() => {
+            let brief = format!("Unexpected arguments: {}\n{}", m.free.join(" "), usage);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+        Ok(m) => m,
+        Err(err) => {
+            let brief = format!("{}\n{}", err, usage);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+    };
+
+    let new_parent = matches.opt_str("move-base").unwrap();
+    if repo
+        .find_branch(&new_parent, git2::BranchType::Local)
+        .is_err()
+    {
+        return Err(Error::general(format!(
+            "{} is not a local branch.",
+            new_parent
+        )));
+    }
+    let current_branch = git::get_current_branch(repo);
+    let old_parent = diffbase.get_parent(&current_branch).map(|p| p.to_string());
+    // g only knows how to print general errors.
+    diffbase
+        .set_diffbase(&current_branch, &new_parent)
+        .map_err(|err| match err.kind {
+            ErrorKind::BranchCantBeDiffbase => Error::general(err.to_string()),
+            _ => err,
+        })?;
+
+    if !matches.opt_present("rebase") {
+        return Ok(());
+    }
+    // Only the commits of the branch itself are moved, not those of its old parent.
+    match old_parent {
+        Some(old_parent) => run_command(&["git", "rebase", "--onto", &new_parent, &old_parent]),
+        None => run_command(&["git", "rebase", &new_parent]),
+    }
+}
+
+/// Shows, sets or clears the diffbase of the current branch.
+pub fn handle_diffbase(
+    args: &[&str],
+    repo: &git2::Repository,
+    diffbase: &mut Diffbase,
+) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optflag("", "unset", "Clear the diffbase, making the branch a root.");
+    let usage = "Usage: g diffbase [options] [PARENT]";
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) if m.free.len() > 1 || (m.opt_present("unset") && !m.free.is_empty()) => {
+            let brief = format!("Expected either --unset or a single PARENT.\n{}", usage);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+        Ok(m) => m,
+        Err(err) => {
+            let brief = format!("{}\n{}", err, usage);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+    };
+
+    let current_branch = git::get_current_branch(repo);
+    if matches.opt_present("unset") {
+        match diffbase.clear_diffbase(&current_branch) {
+            Some(parent) => println!("Cleared the diffbase {} of {}.", parent, current_branch),
+            None => println!("{} has no diffbase.", current_branch),
+        }
+        return Ok(());
+    }
+
+    match matches.free.first() {
+        Some(parent) => {
+            if repo.find_branch(parent, git2::BranchType::Local).is_err() {
+                return Err(Error::general(format!("{} is not a local branch.", parent)));
+            }
+            // g only knows how to print general errors.
+            diffbase
+                .set_diffbase(&current_branch, parent)
+                .map_err(|err| match err.kind {
+                    ErrorKind::BranchCantBeDiffbase => Error::general(err.to_string()),
+                    _ => err,
+                })
+        }
+        None => {
+            match diffbase.get_parent(&current_branch) {
+                Some(parent) => println!("{}", parent),
+                None => println!("{} has no diffbase.", current_branch),
+            }
+            Ok(())
+        }
+    }
 }
 
 /// Moves the diffbase tree upwards (towards the root).
 pub fn handle_up(args: &[&str], repo: &git2::Repository, diffbase: &Diffbase) -> Result<()> {
     let mut opts = getopts::Options::new();
     opts.optflag("r", "root", "Check out root instead of parent.");
+    opts.optflag(
+        "p",
+        "pick",
+        "Choose from all branches between the parent and the root.",
+    );
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(err) => {
@@ -281,7 +1032,22 @@ pub fn handle_up(args: &[&str], repo: &git2::Repository, diffbase: &Diffbase) ->
     };
 
     let current_branch = git::get_current_branch(repo);
-    if matches.opt_present("root") {
+    if matches.opt_present("pick") {
+        // The parent of every ancestor, which ends with the root.
+        let parents: Vec<&str> = diffbase
+            .get_ancestors(&current_branch)
+            .into_iter()
+            .map(|b| diffbase.get_parent(b).unwrap())
+            .collect();
+        if parents.is_empty() {
+            return Err(Error::general(format!(
+                "{} has no diffbase.",
+                current_branch
+            )));
+        }
+        let index = prompt::pick("Check out which branch?", &parents)?;
+        git::checkout(repo, parents[index])
+    } else if matches.opt_present("root") {
         let root = diffbase.get_root(&current_branch).unwrap();
         git::checkout(repo, root)
     } else {
@@ -296,14 +1062,51 @@ pub fn handle_up(args: &[&str], repo: &git2::Repository, diffbase: &Diffbase) ->
 }
 
 /// Moves the diffbase tree down (towards the newest branch) if there is a unique child.
-pub fn handle_down(_: &[&str], repo: &git2::Repository, diffbase: &Diffbase) -> Result<()> {
+pub fn handle_down(args: &[&str], repo: &git2::Repository, diffbase: &Diffbase) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optflag(
+        "a",
+        "all",
+        "Choose from all branches below the current branch. Only lists them if stdin is no \
+         terminal.",
+    );
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(err) => {
+            let brief = format!("{}\nUsage: g down [options]", err);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+    };
+
     let current_branch = git::get_current_branch(repo);
+    if matches.opt_present("all") {
+        let descendants = diffbase.get_descendants(&current_branch);
+        if descendants.is_empty() {
+            return Err(Error::general(format!(
+                "{} has no branches that have it as diffbase.",
+                current_branch
+            )));
+        }
+        if !io::stdin().is_terminal() {
+            for (i, branch) in descendants.iter().enumerate() {
+                println!("  [{}] {}", i + 1, branch);
+            }
+            return Ok(());
+        }
+        let index = prompt::pick("Check out which branch?", &descendants)?;
+        return git::checkout(repo, descendants[index]);
+    }
+
     match diffbase.get_children(&current_branch) {
         Some(ref children) if children.len() == 1 => git::checkout(repo, children[0]),
         Some(ref children) if children.is_empty() => Err(Error::general(format!(
             "{} has no branches that have it as diffbase.",
             current_branch
         ))),
+        Some(ref children) if io::stdin().is_terminal() => {
+            let index = prompt::pick("Check out which branch?", children)?;
+            git::checkout(repo, children[index])
+        }
         Some(ref children) => Err(Error::general(format!(
             "{} has no unique branch that has it as diffbase. \
              Contenders are {}.",
@@ -314,6 +1117,138 @@ pub fn handle_down(_: &[&str], repo: &git2::Repository, diffbase: &Diffbase) ->
     }
 }
 
+/// The message of the commits that 'g wip' parks uncommitted work in.
+const WIP_MESSAGE: &str = "WIP [giti]";
+
+/// Returns true if the tip of 'branch' is a commit made by 'g wip'.
+fn is_wip_commit(repo: &git2::Repository, branch: &str) -> Result<bool> {
+    let commit = repo
+        .find_branch(branch, git2::BranchType::Local)?
+        .get()
+        .peel_to_commit()?;
+    Ok(commit.message().map(|m| m.trim_end()) == Some(WIP_MESSAGE))
+}
+
+/// Parks the uncommitted changes in a WIP commit and checks out the parent branch.
+pub fn handle_wip(repo: &git2::Repository, diffbase: &Diffbase) -> Result<()> {
+    let current_branch = git::get_current_branch(repo);
+    let parent = diffbase
+        .get_parent(&current_branch)
+        .ok_or_else(|| Error::general(format!("{} has no diffbase.", current_branch)))?;
+
+    // Unlike git::status, this also sees untracked files, which 'git add -A' parks as well.
+    let porcelain = communicate_checked(&["git", "status", "--porcelain"])?.stdout;
+    if porcelain.is_empty() {
+        println!("Nothing to park, the working directory is clean.");
+    } else {
+        run_command(&["git", "add", "-A"])?;
+        run_command(&["git", "commit", "-m", WIP_MESSAGE, "--no-verify"])?;
+    }
+    git::checkout(repo, parent)
+}
+
+/// Undoes 'g wip': checks out the unique child branch and turns its WIP commit back into
+/// uncommitted changes. Works on the current branch if it already has the WIP commit.
+pub fn handle_unwip(repo: &git2::Repository, diffbase: &Diffbase) -> Result<()> {
+    let current_branch = git::get_current_branch(repo);
+    let branch = if is_wip_commit(repo, &current_branch)? {
+        current_branch
+    } else {
+        match diffbase.get_children(&current_branch) {
+            Some(ref children) if children.len() == 1 => children[0].to_string(),
+            Some(ref children) if children.len() > 1 => {
+                return Err(Error::general(format!(
+                    "{} has no unique branch that has it as diffbase. \
+                     Contenders are {}.",
+                    current_branch,
+                    children.join(", ")
+                )))
+            }
+            _ => {
+                return Err(Error::general(format!(
+                    "{} has no branches that have it as diffbase.",
+                    current_branch
+                )))
+            }
+        }
+    };
+    if !is_wip_commit(repo, &branch)? {
+        return Err(Error::general(format!(
+            "The tip of {} is not a '{}' commit, refusing to reset it.",
+            branch, WIP_MESSAGE
+        )));
+    }
+
+    if branch != git::get_current_branch(repo) {
+        git::checkout(repo, &branch)?;
+    }
+    run_command(&["git", "reset", "--soft", "HEAD~1"])
+}
+
+/// Infers the diffbase of every branch that has none: its parent is the closest local branch whose
+/// tip is an ancestor of its own tip. Shows the result and asks before storing it.
+pub fn handle_init_stack(repo: &git2::Repository, diffbase: &mut Diffbase) -> Result<()> {
+    let main_branch = git::get_main_branch()?;
+    let main_tip = repo
+        .revparse_single(&format!("origin/{}", main_branch))?
+        .id();
+
+    let mut tips = Vec::new();
+    for branch in git::get_all_local_branch_names(repo)? {
+        // Review branches and main are never part of a stack.
+        if branch == main_branch || branch.starts_with('|') {
+            continue;
+        }
+        let tip = repo.revparse_single(&branch)?.peel_to_commit()?.id();
+        tips.push((branch, tip));
+    }
+    tips.sort();
+
+    let is_ancestor = |ancestor: git2::Oid, descendant: git2::Oid| -> Result<bool> {
+        Ok(ancestor != descendant && repo.graph_descendant_of(descendant, ancestor)?)
+    };
+
+    let mut proposals = Vec::new();
+    for (branch, tip) in &tips {
+        if diffbase.get_parent(branch).is_some() {
+            continue;
+        }
+        let mut parent: Option<&(String, git2::Oid)> = None;
+        for candidate in &tips {
+            // Branches that are already merged into main would be the ancestor of everything.
+            if !is_ancestor(candidate.1, *tip)?
+                || candidate.1 == main_tip
+                || repo.graph_descendant_of(main_tip, candidate.1)?
+            {
+                continue;
+            }
+            parent = match parent {
+                Some(p) if !is_ancestor(p.1, candidate.1)? => Some(p),
+                _ => Some(candidate),
+            };
+        }
+        if let Some((parent, _)) = parent {
+            proposals.push((branch.as_str(), parent.as_str()));
+        }
+    }
+
+    if proposals.is_empty() {
+        println!("Found no stacked branches without diffbase.");
+        return Ok(());
+    }
+    println!("Inferred diffbases:");
+    for (branch, parent) in &proposals {
+        println!("  {} -> {}", branch, parent);
+    }
+    if !prompt::confirm("Store these diffbases?", false)? {
+        return Ok(());
+    }
+    for (branch, parent) in proposals {
+        diffbase.set_diffbase(branch, parent)?;
+    }
+    Ok(())
+}
+
 pub fn handle_pullc(args: &[&str], repo: &git2::Repository, diffbase: &Diffbase) -> Result<()> {
     let mut opts = getopts::Options::new();
     opts.optflag(
@@ -321,6 +1256,17 @@ pub fn handle_pullc(args: &[&str], repo: &git2::Repository, diffbase: &Diffbase)
         "push",
         "Also push all branches that have a upstream and are changed.",
     );
+    opts.optflag(
+        "",
+        "abort-on-conflict",
+        "Run 'git merge --abort' if a merge has conflicts, instead of leaving it to be resolved.",
+    );
+    opts.optflag(
+        "",
+        "no-merge",
+        "Only bring branches up to date with their upstreams, do not merge main and the parents \
+         into their children. This replaces the normal mode, it is not done in addition.",
+    );
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(err) => {
@@ -328,68 +1274,133 @@ pub fn handle_pullc(args: &[&str], repo: &git2::Repository, diffbase: &Diffbase)
             return Err(Error::general(opts.usage(&brief)));
         }
     };
-    let do_push = matches.opt_present("push");
+    let options = PullTreeOptions {
+        push: matches.opt_present("push"),
+        merge_parents: !matches.opt_present("no-merge"),
+        abort_on_conflict: matches.opt_present("abort-on-conflict"),
+    };
+    pull_tree(repo, diffbase, &options)?;
+    Ok(())
+}
 
+/// What pull_tree does besides merging the upstreams into the branches.
+struct PullTreeOptions {
+    /// Push the branches that have an upstream and changed.
+    push: bool,
+    /// Merge main into the roots and every parent into its children.
+    merge_parents: bool,
+    /// Abort a merge that has conflicts, see merge_or_abort.
+    abort_on_conflict: bool,
+}
+
+/// Merges 'branch' into the checked out 'into' like git::merge. With 'abort_on_conflict', a merge
+/// that stopped is aborted before the error is returned, so the work tree is not left mid-merge.
+fn merge_or_abort(
+    branch: &str,
+    into: &str,
+    repo: &git2::Repository,
+    abort_on_conflict: bool,
+) -> Result<()> {
+    let result = git::merge(branch, into, repo);
+    match result {
+        Err(err) if abort_on_conflict && repo.state() == git2::RepositoryState::Merge => {
+            run_command(&["git", "merge", "--abort"])?;
+            Err(Error::general(format!(
+                "{}\nThe merge was aborted.",
+                err.description()
+            )))
+        }
+        other => other,
+    }
+}
+
+/// Merges their upstream into all branches, and then main into every diffbase root and every parent
+/// into its children. Returns the pushed branches.
+fn pull_tree(
+    repo: &git2::Repository,
+    diffbase: &Diffbase,
+    options: &PullTreeOptions,
+) -> Result<Vec<String>> {
     let local_branches = git::get_all_local_branches(repo)?;
+    let main_branch = git::get_main_branch()?;
+    let branch_at_start = git::get_current_branch(repo);
 
-    // Merge main into the root.
-    run_command(&["git", "fetch"])?;
+    // The network round trips dominate the runtime, so they are batched: first every remote that
+    // any branch tracks is fetched, all at the same time. Then the branches are merged one after
+    // the other in the work tree, a parent always before its children, merging the already
+    // fetched upstream instead of pulling. Only once every merge succeeded, all changed branches
+    // are pushed at the same time. If a merge fails, nothing is pushed.
+    let mut remotes: BTreeSet<String> = local_branches
+        .keys()
+        .filter_map(|b| git::get_upstream_remote(repo, b))
+        .collect();
+    remotes.insert("origin".to_string());
+    git::fetch_remotes(&remotes.into_iter().collect::<Vec<_>>())?;
 
     let mut branches_todo: BTreeSet<&str> = local_branches.keys().map(|s| s as &str).collect();
-    let main_branch = git::get_main_branch();
-    let branch_at_start = git::get_current_branch(repo);
+    let mut to_push: Vec<&str> = Vec::new();
 
-    let has_upstream = |s| {
-        if let Some(b) = local_branches.get(s) {
-            return b.upstream.is_some();
-        }
-        false
-    };
+    let upstream = |s: &str| local_branches.get(s).and_then(|b| b.upstream.as_deref());
 
     while !branches_todo.is_empty() {
         let current_branch = branches_todo.pop_last().unwrap();
 
         let root = diffbase.get_root(current_branch).unwrap();
+        branches_todo.remove(root);
 
-        // Sync the root branch.
-        git::checkout(repo, root)?;
-        if has_upstream(root) {
-            run_command(&["git", "pull"])?;
+        // Sync the root branch. Without merging, there is nothing to do for branches without an
+        // upstream, so they are not even checked out.
+        if options.merge_parents || upstream(root).is_some() {
+            git::checkout(repo, root)?;
+        }
+        if let Some(u) = upstream(root) {
+            merge_or_abort(u, root, repo, options.abort_on_conflict)?;
+            to_push.push(root);
         }
 
-        // No matter if we have an upstream, after we pulled our upstream, we have to merge the
-        // root of our repo.
-        run_command(&["git", "merge", &format!("origin/{main_branch}")])?;
-        if do_push && has_upstream(root) {
-            run_command(&["git", "push"])?;
+        // No matter if we have an upstream, after we synced with our upstream, we have to merge
+        // the root of our repo.
+        if options.merge_parents {
+            merge_or_abort(
+                &format!("origin/{main_branch}"),
+                root,
+                repo,
+                options.abort_on_conflict,
+            )?;
         }
 
-        fn merge_parent_into_children(
+        fn merge_parent_into_children<'a>(
             parent: &str,
-            diffbase: &Diffbase,
+            diffbase: &'a Diffbase,
             repo: &git2::Repository,
             local_branches: &HashMap<String, git::BranchInfo>,
-            do_push: bool,
             todo: &mut BTreeSet<&str>,
+            to_push: &mut Vec<&'a str>,
+            options: &PullTreeOptions,
         ) -> Result<()> {
-            let has_upstream = |s| {
-                if let Some(b) = local_branches.get(s) {
-                    return b.upstream.is_some();
-                }
-                false
-            };
+            let upstream = |s: &str| local_branches.get(s).and_then(|b| b.upstream.as_deref());
 
             for child in diffbase.get_children(parent).unwrap() {
-                git::checkout(repo, child)?;
-                if has_upstream(child) {
-                    run_command(&["git", "pull"])?;
+                if options.merge_parents || upstream(child).is_some() {
+                    git::checkout(repo, child)?;
+                }
+                if let Some(u) = upstream(child) {
+                    merge_or_abort(u, child, repo, options.abort_on_conflict)?;
+                    to_push.push(child);
                 }
-                git::merge(parent, repo)?;
-                if do_push && has_upstream(child) {
-                    run_command(&["git", "push"])?;
+                if options.merge_parents {
+                    merge_or_abort(parent, child, repo, options.abort_on_conflict)?;
                 }
                 todo.remove(child);
-                merge_parent_into_children(child, diffbase, repo, local_branches, do_push, todo)?;
+                merge_parent_into_children(
+                    child,
+                    diffbase,
+                    repo,
+                    local_branches,
+                    todo,
+                    to_push,
+                    options,
+                )?;
             }
             Ok(())
         }
@@ -399,14 +1410,65 @@ pub fn handle_pullc(args: &[&str], repo: &git2::Repository, diffbase: &Diffbase)
             diffbase,
             repo,
             &local_branches,
-            do_push,
             &mut branches_todo,
+            &mut to_push,
+            options,
         )?;
     }
 
     if git::get_current_branch(repo) != branch_at_start {
         git::checkout(repo, &branch_at_start)?;
     }
+
+    if !options.push {
+        return Ok(Vec::new());
+    }
+    // Pushing a branch that is even with its upstream would be a no-op.
+    let rev = |name: &str| repo.revparse_single(name).ok().map(|o| o.id());
+    to_push.retain(|b| rev(b) != rev(upstream(b).unwrap()));
+    git::push_to_upstreams(&to_push)?;
+    Ok(to_push.into_iter().map(|b| b.to_string()).collect())
+}
+
+pub fn handle_sync(args: &[&str], repo: &git2::Repository, diffbase: &Diffbase) -> Result<()> {
+    let opts = getopts::Options::new();
+    if let Err(err) = opts.parse(&args[1..]) {
+        let brief = format!("{}\nUsage: g sync", err);
+        return Err(Error::general(opts.usage(&brief)));
+    }
+
+    let branches: Vec<String> = git::get_all_local_branches(repo)?.into_keys().collect();
+    let rev = |name: &str| repo.revparse_single(name).ok().map(|o| o.id());
+    let before: HashMap<&str, _> = branches.iter().map(|b| (b as &str, rev(b))).collect();
+
+    let options = PullTreeOptions {
+        push: true,
+        merge_parents: true,
+        abort_on_conflict: false,
+    };
+    let pushed = pull_tree(repo, diffbase, &options)?;
+
+    let mut rows: Vec<(&str, bool, bool)> = branches
+        .iter()
+        .map(|b| (b as &str, before[b as &str] != rev(b), pushed.contains(b)))
+        .collect();
+    rows.sort();
+    let width = rows
+        .iter()
+        .map(|(b, _, _)| b.len())
+        .max()
+        .unwrap_or(0)
+        .max(6);
+    let yes_no = |v| if v { "yes" } else { "no" };
+    println!("\n{:width$}  Updated  Pushed", "Branch");
+    for (branch, updated, pushed) in rows {
+        println!(
+            "{:width$}  {:7}  {}",
+            branch,
+            yes_no(updated),
+            yes_no(pushed)
+        );
+    }
     Ok(())
 }
 
@@ -441,7 +1503,16 @@ fn extract_option<'a>(
 
 #[cfg(test)]
 mod tests {
-    use super::extract_option;
+    use super::{
+        branch_created_at, checkout_route, extract_option, new_branch_diffbase, shared_git_dir,
+        CheckoutRoute, Diffbase, DiffbaseJson, ErrorKind, MergeRequest,
+    };
+    use crate::gitlab;
+    use crate::test_util::TestRepo;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
 
     #[test]
     fn test_extract_option() {
@@ -451,4 +1522,364 @@ mod tests {
         assert_eq!(options, ["--export"]);
         assert_eq!(positional, ["foo", "flah"]);
     }
+
+    #[test]
+    fn test_checkout_route() {
+        let route = |args: &'static [&'static str]| {
+            let (new_branch_name, ignored, positional) = extract_option(Some("-b"), args);
+            checkout_route(new_branch_name, &ignored, &positional)
+        };
+
+        assert_eq!(route(&["feature"]), CheckoutRoute::Branch("feature"));
+        assert_eq!(route(&["--detach", "feature"]), CheckoutRoute::Passthrough);
+        assert_eq!(route(&["--detach"]), CheckoutRoute::Passthrough);
+        assert_eq!(route(&["-b", "feature"]), CheckoutRoute::Passthrough);
+        assert_eq!(
+            route(&["-b", "feature", "origin/main"]),
+            CheckoutRoute::Passthrough
+        );
+        assert_eq!(route(&["main", "--", "file"]), CheckoutRoute::Passthrough);
+        assert_eq!(route(&["-"]), CheckoutRoute::Previous);
+        assert_eq!(route(&["-", "feature"]), CheckoutRoute::Passthrough);
+    }
+
+    #[test]
+    fn test_new_branch_diffbase() {
+        let is_local_branch = |b: &str| b == "other-branch" || b == "current";
+
+        let args = ["-b", "feature"];
+        let (_, _, positional) = extract_option(Some("-b"), &args);
+        assert_eq!(
+            new_branch_diffbase(&positional, "current", is_local_branch),
+            Some("current")
+        );
+
+        let args = ["-b", "feature", "other-branch"];
+        let (_, _, positional) = extract_option(Some("-b"), &args);
+        assert_eq!(
+            new_branch_diffbase(&positional, "current", is_local_branch),
+            Some("other-branch")
+        );
+
+        let args = ["-b", "feature", "--track", "origin/main"];
+        let (_, _, positional) = extract_option(Some("-b"), &args);
+        assert_eq!(
+            new_branch_diffbase(&positional, "current", is_local_branch),
+            None
+        );
+    }
+
+    /// Returns a diffbase with the (branch, parent) 'edges' that is not backed by a repository.
+    fn diffbase_with(edges: &[(&str, &str)]) -> Diffbase {
+        let mut diffbase = Diffbase {
+            entries: HashMap::new(),
+            json_file_path: PathBuf::new(),
+            load_problems: Vec::new(),
+            _lock: None,
+        };
+        for (branch, parent) in edges {
+            diffbase.entries.entry(parent.to_string()).or_default();
+            diffbase
+                .entries
+                .entry(branch.to_string())
+                .or_default()
+                .parent = Some(parent.to_string());
+            diffbase
+                .entries
+                .get_mut(*parent)
+                .unwrap()
+                .children
+                .push(branch.to_string());
+        }
+        diffbase
+    }
+
+    #[test]
+    fn test_set_diffbase_rejects_cycles() {
+        let mut diffbase = diffbase_with(&[("b", "a"), ("c", "b")]);
+        let err = diffbase.set_diffbase_quiet("a", "c").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DiffbaseCycle);
+        assert_eq!(
+            err.description(),
+            "c cannot be the diffbase of a, since a is already a diffbase of c."
+        );
+        assert_eq!(
+            diffbase.set_diffbase_quiet("a", "a").unwrap_err().kind,
+            ErrorKind::DiffbaseCycle
+        );
+        assert_eq!(diffbase.get_parent("a"), None);
+        assert_eq!(diffbase.get_root("c"), Some("a"));
+    }
+
+    #[test]
+    fn test_shared_git_dir() {
+        assert_eq!(
+            shared_git_dir(Path::new("/src/repo/.git/worktrees/feature/")),
+            Path::new("/src/repo/.git")
+        );
+        assert_eq!(
+            shared_git_dir(Path::new("/src/repo/.git/")),
+            Path::new("/src/repo/.git")
+        );
+        assert_eq!(
+            shared_git_dir(Path::new("/src/bare.git")),
+            Path::new("/src/bare.git")
+        );
+    }
+
+    #[test]
+    fn test_created_at() {
+        let legacy: Vec<DiffbaseJson> =
+            serde_json::from_str(r#"[{"branch": "a", "diffbase": null, "merge_request": null}]"#)
+                .unwrap();
+        assert_eq!(legacy[0].created_at, None);
+
+        let mut diffbase = diffbase_with(&[("b", "a"), ("c", "a")]);
+        for (branch, created_at) in [
+            ("a", "2024-03-01T10:00:00+01:00"),
+            ("c", "2024-01-15T08:30:00+00:00"),
+        ] {
+            diffbase.entries.get_mut(branch).unwrap().created_at = Some(created_at.to_string());
+        }
+        let branches: Vec<&str> = diffbase
+            .get_branches_by_age()
+            .into_iter()
+            .map(|(b, _)| b)
+            .collect();
+        assert_eq!(branches, vec!["c", "a", "b"]);
+    }
+
+    #[test]
+    fn test_branch_created_at() {
+        let test_repo = TestRepo::new();
+        let repo = &test_repo.repo;
+        let commit = repo.find_commit(test_repo.commit_on_head("init")).unwrap();
+        repo.branch("a", &commit, false).unwrap();
+        let created_at = branch_created_at(repo, "a").unwrap();
+        let created_at = chrono::DateTime::parse_from_rfc3339(&created_at).unwrap();
+        assert!((chrono::Local::now().timestamp() - created_at.timestamp()).abs() < 60);
+
+        assert_eq!(branch_created_at(repo, "unknown"), None);
+    }
+
+    #[test]
+    fn test_merge_request_round_trip() {
+        let url = "https://gitlab.com/p/-/merge_requests/7";
+        let entries = vec![DiffbaseJson {
+            branch: "a".to_string(),
+            diffbase: None,
+            merge_request: Some(MergeRequest::GitLab(gitlab::PullRequestId {
+                url: url.to_string(),
+            })),
+            created_at: None,
+            issue: None,
+        }];
+        let json = serde_json::to_string(&entries).unwrap();
+        let loaded: Vec<DiffbaseJson> = serde_json::from_str(&json).unwrap();
+        match &loaded[0].merge_request {
+            Some(MergeRequest::GitLab(mr_id)) => assert_eq!(mr_id.url, url),
+            other => panic!("Expected a GitLab merge request, got {:?}", other),
+        }
+
+        // Entries written before merge requests were tracked have none.
+        let legacy: Vec<DiffbaseJson> =
+            serde_json::from_str(r#"[{"branch": "a", "diffbase": null}]"#).unwrap();
+        assert!(legacy[0].merge_request.is_none());
+    }
+
+    #[test]
+    fn test_validate_and_repair() {
+        let mut diffbase = diffbase_with(&[("b", "a"), ("c", "b")]);
+        assert!(diffbase.validate().is_empty());
+
+        diffbase.entries.get_mut("c").unwrap().parent = Some("gone".to_string());
+        diffbase
+            .entries
+            .get_mut("a")
+            .unwrap()
+            .children
+            .push("d".to_string());
+        diffbase.entries.insert("x".to_string(), Default::default());
+        diffbase.entries.insert("y".to_string(), Default::default());
+        diffbase.entries.get_mut("x").unwrap().parent = Some("y".to_string());
+        diffbase.entries.get_mut("y").unwrap().parent = Some("x".to_string());
+        diffbase
+            .entries
+            .get_mut("y")
+            .unwrap()
+            .children
+            .push("x".to_string());
+        diffbase
+            .entries
+            .get_mut("x")
+            .unwrap()
+            .children
+            .push("y".to_string());
+        assert_eq!(
+            diffbase.validate(),
+            vec![
+                "The child d of a does not exist.",
+                "b lists c as a child, but its diffbase is gone.",
+                "The diffbase gone of c does not exist.",
+                "x is its own ancestor.",
+                "y is its own ancestor.",
+            ]
+        );
+
+        diffbase.repair();
+        assert!(diffbase.validate().is_empty());
+        assert_eq!(diffbase.get_parent("c"), None);
+        assert_eq!(diffbase.get_children("a"), Some(vec!["b"]));
+        assert_eq!(diffbase.get_children("b"), Some(vec![]));
+        // The cycle is broken up, but one of the links is kept.
+        assert_eq!(
+            diffbase.get_ancestors("x").len() + diffbase.get_ancestors("y").len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_get_ancestors() {
+        let diffbase = diffbase_with(&[("b", "a"), ("c", "b"), ("d", "a")]);
+        assert_eq!(diffbase.get_ancestors("c"), vec!["c", "b"]);
+        assert_eq!(diffbase.get_ancestors("d"), vec!["d"]);
+        assert!(diffbase.get_ancestors("a").is_empty());
+        assert!(diffbase.get_ancestors("unknown").is_empty());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut diffbase = diffbase_with(&[("b", "a"), ("c", "b"), ("d", "b"), ("e", "c")]);
+        diffbase.remove("b");
+        assert_eq!(diffbase.get_parent("c"), Some("a"));
+        assert_eq!(diffbase.get_parent("d"), Some("a"));
+        assert_eq!(diffbase.get_children("a"), Some(vec!["c", "d"]));
+        assert_eq!(diffbase.get_children("b"), None);
+        assert_eq!(diffbase.get_parent("e"), Some("c"));
+
+        // Children of a root become roots.
+        diffbase.remove("a");
+        assert_eq!(diffbase.get_parent("c"), None);
+        assert_eq!(diffbase.get_parent("d"), None);
+        assert_eq!(diffbase.get_root("e"), Some("c"));
+
+        // Removing an unknown branch is fine.
+        diffbase.remove("x");
+    }
+
+    #[test]
+    fn test_depth() {
+        let diffbase = diffbase_with(&[("b", "a"), ("c", "b"), ("d", "a")]);
+        assert_eq!(diffbase.depth("a"), 0);
+        assert_eq!(diffbase.depth("d"), 1);
+        assert_eq!(diffbase.depth("c"), 2);
+        assert_eq!(diffbase.depth("x"), 0);
+    }
+
+    #[test]
+    fn test_get_descendants() {
+        let diffbase = diffbase_with(&[("b", "a"), ("c", "b"), ("d", "a"), ("e", "c")]);
+        assert_eq!(diffbase.get_descendants("a"), vec!["b", "c", "e", "d"]);
+        assert_eq!(diffbase.get_descendants("c"), vec!["e"]);
+        assert!(diffbase.get_descendants("d").is_empty());
+    }
+
+    #[test]
+    fn test_clear_diffbase() {
+        let mut diffbase = diffbase_with(&[("b", "a"), ("c", "b"), ("d", "a")]);
+        assert_eq!(diffbase.clear_diffbase("b"), Some("a".to_string()));
+        assert_eq!(diffbase.get_parent("b"), None);
+        assert_eq!(diffbase.get_children("a"), Some(vec!["d"]));
+        // The children move along.
+        assert_eq!(diffbase.get_children("b"), Some(vec!["c"]));
+        assert_eq!(diffbase.get_root("c"), Some("b"));
+
+        assert_eq!(diffbase.clear_diffbase("b"), None);
+        assert_eq!(diffbase.clear_diffbase("x"), None);
+    }
+
+    #[test]
+    fn test_render_tree() {
+        let mut diffbase = diffbase_with(&[("b", "a"), ("c", "b"), ("d", "a"), ("y", "x")]);
+        diffbase.set_merge_request(
+            "b",
+            MergeRequest::GitLab(gitlab::PullRequestId {
+                url: "https://gitlab.com/p/-/merge_requests/1".to_string(),
+            }),
+        );
+        assert_eq!(
+            diffbase.render_tree("c"),
+            "a\n\
+             ├── b (https://gitlab.com/p/-/merge_requests/1)\n\
+             │   └── c <-\n\
+             └── d\n\
+             x\n\
+             └── y\n"
+        );
+
+        let diffbase = diffbase_with(&[("b", "a"), ("a", "b")]);
+        assert_eq!(diffbase.render_tree("c"), "a\n└── b\n    └── a (cycle)\n");
+    }
+
+    #[test]
+    fn test_concurrent_writers_do_not_clobber() {
+        let test_repo = TestRepo::new();
+        let (dir, repo) = (&test_repo.dir, &test_repo.repo);
+        let commit = repo.find_commit(test_repo.commit_on_head("init")).unwrap();
+        repo.branch("a", &commit, false).unwrap();
+        repo.branch("b", &commit, false).unwrap();
+
+        let writers: Vec<_> = ["a", "b"]
+            .iter()
+            .map(|branch| {
+                let path = dir.path().to_path_buf();
+                thread::spawn(move || {
+                    let repo = git2::Repository::open(path).unwrap();
+                    let mut diffbase = Diffbase::new_locked(&repo).unwrap();
+                    diffbase.set_merge_request(
+                        branch,
+                        MergeRequest::GitLab(gitlab::PullRequestId {
+                            url: format!("https://gitlab.com/p/-/merge_requests/{}", branch),
+                        }),
+                    );
+                    // Give the other writer a chance to read the file in the meantime.
+                    thread::sleep(Duration::from_millis(100));
+                    diffbase.write_to_disk().unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let diffbase = Diffbase::new(repo).unwrap();
+        assert!(diffbase.get_merge_request("a").is_some());
+        assert!(diffbase.get_merge_request("b").is_some());
+    }
+
+    #[test]
+    fn test_prune_drops_deleted_branches() {
+        let test_repo = TestRepo::new();
+        let repo = &test_repo.repo;
+        let commit = repo.find_commit(test_repo.commit_on_head("init")).unwrap();
+        repo.branch("a", &commit, false).unwrap();
+        let mut diffbase = Diffbase::new_locked(repo).unwrap();
+        diffbase.set_merge_request(
+            "a",
+            MergeRequest::GitLab(gitlab::PullRequestId {
+                url: "https://gitlab.com/p/-/merge_requests/1".to_string(),
+            }),
+        );
+        diffbase.write_to_disk().unwrap();
+        drop(diffbase);
+
+        repo.find_branch("a", git2::BranchType::Local)
+            .unwrap()
+            .delete()
+            .unwrap();
+        assert!(Diffbase::new(repo).unwrap().has_load_problems());
+
+        Diffbase::prune(repo).unwrap();
+        assert!(!Diffbase::new(repo).unwrap().has_load_problems());
+    }
 }
@@ -1,7 +1,13 @@
 /// Tools to shell out to external commands.
+use super::config;
 use super::error::{Error, Result};
+use std::env;
+use std::io;
 use std::path::Path;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use term;
 
@@ -11,9 +17,49 @@ enum PrintCommands {
     No,
 }
 
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Makes every command that is run echo its command line, not only those run by 'run_command'.
+/// This is set by 'g --verbose'.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Prints the command line 'program' 'args' in cyan to 'terminal'.
+fn print_command<T: term::Terminal + ?Sized>(terminal: &mut T, program: &str, args: &[&str]) {
+    terminal.fg(term::color::CYAN).unwrap();
+    write!(terminal, "=> Running: {} {}", program, args.join(" ")).unwrap();
+    terminal.reset().unwrap();
+    writeln!(terminal).unwrap();
+}
+
+/// Returns the editor to use: $GIT_EDITOR, $VISUAL, $EDITOR, git's core.editor and finally the
+/// system's default, whichever is set first.
+fn find_editor() -> Result<String> {
+    let from_env = ["GIT_EDITOR", "VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|v| env::var(v).ok())
+        .find(|e| !e.trim().is_empty());
+    let editor = from_env
+        .or_else(|| config::get_string("core.editor"))
+        .or_else(|| default_editor::get().ok())
+        .filter(|e| !e.trim().is_empty());
+    editor.ok_or_else(|| {
+        Error::general(
+            "No editor found. Checked $GIT_EDITOR, $VISUAL, $EDITOR and git's core.editor, \
+             please set one of them."
+                .to_string(),
+        )
+    })
+}
+
 pub fn run_editor(path: &Path) -> Result<()> {
-    let editor = default_editor::get()?;
-    let mut it = editor.split(' ');
+    let editor = find_editor()?;
+    let mut it = editor.split_whitespace();
     let cmd = it.next().unwrap();
     let mut args: Vec<String> = it.map(|s| s.to_string()).collect();
     args.push(path.to_str().unwrap().to_string());
@@ -26,6 +72,38 @@ pub fn dispatch_to(command: &str, args: &[&str]) -> Result<()> {
     shell_out(command, args, PrintCommands::No)
 }
 
+/// Like 'dispatch_to', but kills 'command' if it does not finish within 'timeout'.
+pub fn dispatch_to_with_timeout(command: &str, args: &[&str], timeout: Duration) -> Result<()> {
+    if is_verbose() {
+        print_command(&mut *term::stdout().unwrap(), command, args);
+    }
+    let mut child = process::Command::new(command)
+        .args(args)
+        .stdin(process::Stdio::inherit())
+        .stdout(process::Stdio::inherit())
+        .stderr(process::Stdio::inherit())
+        .spawn()
+        .map_err(|e| spawn_error(command, e))?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return exit_result(command, status);
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(Error::general(format!(
+                "'{} {}' did not finish within {}s and was killed.",
+                command,
+                args.join(" "),
+                timeout.as_secs()
+            )));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
 /// Runs the command and echoing the command line.
 pub fn run_command(args: &[&str]) -> Result<()> {
     shell_out(args[0], &args[1..], PrintCommands::Yes)
@@ -33,18 +111,50 @@ pub fn run_command(args: &[&str]) -> Result<()> {
 
 /// Runs the command, but captures stdout & stdin. Named after the python function.
 pub fn communicate(args: &[&str]) -> Result<process::Output> {
-    Ok(process::Command::new(args[0]).args(&args[1..]).output()?)
+    if is_verbose() {
+        // stdout might be parsed, e.g. for 'g prs --format json'.
+        print_command(&mut *term::stderr().unwrap(), args[0], &args[1..]);
+    }
+    process::Command::new(args[0])
+        .args(&args[1..])
+        .output()
+        .map_err(|e| spawn_error(args[0], e))
+}
+
+/// Like 'communicate', but fails if the command does not exit successfully. Since the output was
+/// captured, the error contains what the command printed to stderr.
+pub fn communicate_checked(args: &[&str]) -> Result<process::Output> {
+    let output = communicate(args)?;
+    if output.status.success() {
+        return Ok(output);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(Error::general(format!(
+        "'{}' failed with {}: {}",
+        args.join(" "),
+        output.status,
+        stderr.trim()
+    )))
+}
+
+/// Turns the error of starting 'program' into an Error. A missing program gets a hint on how to
+/// fix it, since that usually means git is not installed.
+fn spawn_error(program: &str, err: io::Error) -> Error {
+    if err.kind() == io::ErrorKind::NotFound {
+        return Error::general(format!(
+            "Could not find '{}'. Please install it or make sure it is in your PATH.",
+            program
+        ));
+    }
+    Error::general(format!("Could not run '{}': {}", program, err))
 }
 
 /// Dispatches to 'program' with 'str'. 'print' decides if the command lines are echoed.
 fn shell_out(program: &str, args: &[&str], print: PrintCommands) -> Result<()> {
     match print {
-        PrintCommands::Yes => {
-            let mut terminal = term::stdout().unwrap();
-            terminal.fg(term::color::CYAN).unwrap();
-            write!(terminal, "=> Running: {} {}", program, args.join(" ")).unwrap();
-            terminal.reset().unwrap();
-            writeln!(terminal).unwrap();
+        PrintCommands::Yes => print_command(&mut *term::stdout().unwrap(), program, args),
+        PrintCommands::No if is_verbose() => {
+            print_command(&mut *term::stdout().unwrap(), program, args)
         }
         PrintCommands::No => {}
     }
@@ -55,16 +165,9 @@ fn shell_out(program: &str, args: &[&str], print: PrintCommands) -> Result<()> {
         .stdout(process::Stdio::inherit())
         .stderr(process::Stdio::inherit())
         .spawn()
-        .unwrap_or_else(|e| panic!("failed to execute: {}", e));
+        .map_err(|e| spawn_error(program, e))?;
 
-    let result = match child.wait().unwrap().code() {
-        Some(0) => Ok(()),
-        Some(a) => Err(Error::subcommand_fail(program, a)),
-        None => Err(Error::general(format!(
-            "{} was terminated by a signal.",
-            program
-        ))),
-    };
+    let result = exit_result(program, child.wait()?);
 
     match print {
         PrintCommands::Yes => {
@@ -75,3 +178,15 @@ fn shell_out(program: &str, args: &[&str], print: PrintCommands) -> Result<()> {
     }
     result
 }
+
+/// Turns the exit status of 'program' into a Result.
+fn exit_result(program: &str, status: process::ExitStatus) -> Result<()> {
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(a) => Err(Error::subcommand_fail(program, a)),
+        None => Err(Error::general(format!(
+            "{} was terminated by a signal.",
+            program
+        ))),
+    }
+}
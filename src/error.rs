@@ -7,12 +7,15 @@ pub enum ErrorKind {
     GeneralError,
     SubcommandFailed,
     BranchCantBeDiffbase,
+    DiffbaseCycle,
 }
 
 #[derive(Debug)]
 pub struct Error {
     pub description: String,
     pub kind: ErrorKind,
+    // The error this one was converted from, if any.
+    pub source: Option<Box<dyn error::Error + Send + Sync>>,
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -22,6 +25,7 @@ impl Error {
         Error {
             description: s,
             kind: ErrorKind::GeneralError,
+            source: None,
         }
     }
 
@@ -29,6 +33,7 @@ impl Error {
         Error {
             description: format!("{} exited with {}", command, code),
             kind: ErrorKind::SubcommandFailed,
+            source: None,
         }
     }
 
@@ -36,12 +41,43 @@ impl Error {
         Error {
             description: format!("{} cannot be a diffbase.", branch),
             kind: ErrorKind::BranchCantBeDiffbase,
+            source: None,
+        }
+    }
+
+    pub fn diffbase_cycle(branch: &str, diffbase: &str) -> Error {
+        Error {
+            description: format!(
+                "{} cannot be the diffbase of {}, since {} is already a diffbase of {}.",
+                diffbase, branch, branch, diffbase
+            ),
+            kind: ErrorKind::DiffbaseCycle,
+            source: None,
         }
     }
 
     pub fn description(&self) -> &str {
         &self.description
     }
+
+    /// Returns the error this one was converted from. This is not std::error::Error::source,
+    /// because implementing std::error::Error would conflict with the blanket From impl below.
+    pub fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|e| e as &(dyn error::Error + 'static))
+    }
+
+    /// Returns the messages of the errors that caused this one, outermost first.
+    pub fn causes(&self) -> Vec<String> {
+        let mut causes = Vec::new();
+        let mut current = self.source();
+        while let Some(err) = current {
+            causes.push(err.to_string());
+            current = err.source();
+        }
+        causes
+    }
 }
 
 impl fmt::Display for Error {
@@ -50,8 +86,45 @@ impl fmt::Display for Error {
     }
 }
 
-impl<T: error::Error> From<T> for Error {
+impl<T: error::Error + Send + Sync + 'static> From<T> for Error {
     fn from(err: T) -> Error {
-        Error::general(err.to_string().to_string())
+        Error {
+            description: err.to_string(),
+            kind: ErrorKind::GeneralError,
+            source: Some(Box::new(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use std::{error, fmt};
+
+    #[derive(Debug)]
+    struct Failure(&'static str, Option<Box<Failure>>);
+
+    impl fmt::Display for Failure {
+        fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(formatter, "{}", self.0)
+        }
+    }
+
+    impl error::Error for Failure {
+        fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+            self.1
+                .as_deref()
+                .map(|e| e as &(dyn error::Error + 'static))
+        }
+    }
+
+    #[test]
+    fn test_causes() {
+        let cause = Failure("connection reset", None);
+        let err = Error::from(Failure("request failed", Some(Box::new(cause))));
+        assert_eq!(err.description(), "request failed");
+        assert_eq!(err.causes(), ["request failed", "connection reset"]);
+
+        assert!(Error::general("plain".to_string()).causes().is_empty());
     }
 }
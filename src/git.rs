@@ -1,41 +1,93 @@
+use crate::config;
 use crate::diffbase;
 use crate::diffbase::MergeRequest;
-use crate::dispatch::{communicate, dispatch_to, run_command, run_editor};
+use crate::dispatch::{
+    communicate, communicate_checked, dispatch_to, dispatch_to_with_timeout, run_command,
+    run_editor,
+};
+use crate::prompt;
 use crate::Error;
+use crate::ErrorKind;
 use crate::Result;
-use crate::{github, gitlab};
+use crate::{bitbucket, github, gitlab};
 use chrono::{Local, NaiveDate, TimeZone};
+use futures::future::join_all;
 use git2;
-use std::collections::{HashMap, HashSet};
-use std::io::Write;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
 use std::str;
+use std::time::Duration;
 use tokio::try_join;
 use webbrowser;
 
-/// Calls git merge and checks if the merge was successful.
-pub fn merge(branch: &str, repo: &git2::Repository) -> Result<()> {
-    run_command(&["git", "merge", branch])?;
+/// Merges 'branch' into 'into', which must be checked out. If the merge stops, the error names
+/// both branches and the conflicted paths.
+pub fn merge(branch: &str, into: &str, repo: &git2::Repository) -> Result<()> {
+    // git exits with an error on conflicts, so the state is checked first to give a better error.
+    let result = run_command(&["git", "merge", branch]);
     if repo.state() != git2::RepositoryState::Clean {
-        return Err(Error::general(
-            "git merge did not complete cleanly.".to_string(),
-        ));
+        let mut description = format!("Merging {} into {} did not complete cleanly.", branch, into);
+        let conflicts = conflicted_paths(repo)?;
+        if !conflicts.is_empty() {
+            description.push_str(" Conflicted paths:");
+            for path in conflicts {
+                description.push_str(&format!("\n  {}", path));
+            }
+        }
+        return Err(Error::general(description));
     }
-    Ok(())
+    result
 }
 
-pub fn get_main_branch() -> String {
-    let out = String::from_utf8(
-        communicate(&["git", "symbolic-ref", "refs/remotes/origin/HEAD"])
-            .unwrap()
-            .stdout,
-    )
-    .unwrap();
-    let line = out
-        .lines()
-        .next()
-        .expect("No HEAD branch for remote 'origin'");
-    line.trim().split('/').last().unwrap().to_string()
+/// Returns the paths that have conflicts in the index, sorted.
+fn conflicted_paths(repo: &git2::Repository) -> Result<Vec<String>> {
+    let mut index = repo.index()?;
+    // The index was changed by the git command line, not through libgit2.
+    index.read(true)?;
+    let mut paths = BTreeSet::new();
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        let entry = conflict.our.or(conflict.their).or(conflict.ancestor);
+        if let Some(entry) = entry {
+            paths.insert(String::from_utf8_lossy(&entry.path).into_owned());
+        }
+    }
+    Ok(paths.into_iter().collect())
+}
+
+/// Returns the name of the main branch, e.g. 'main'. It is taken from giti.mainBranch, or else
+/// from what origin/HEAD points to. Since many clones never set origin/HEAD, origin/main and
+/// origin/master are tried last.
+pub fn get_main_branch() -> Result<String> {
+    if let Some(branch) = config::get_string("giti.mainBranch").filter(|b| !b.is_empty()) {
+        return Ok(branch);
+    }
+
+    // Fails if origin/HEAD is not set.
+    if let Ok(out) = communicate_checked(&["git", "symbolic-ref", "refs/remotes/origin/HEAD"]) {
+        let out = String::from_utf8(out.stdout)?;
+        if let Some(line) = out.lines().next() {
+            return Ok(line.trim().split('/').next_back().unwrap().to_string());
+        }
+    }
+
+    if let Ok(repo) = git2::Repository::discover(".") {
+        for candidate in ["main", "master"] {
+            if repo
+                .revparse_single(&format!("origin/{}", candidate))
+                .is_ok()
+            {
+                return Ok(candidate.to_string());
+            }
+        }
+    }
+    Err(Error::general(
+        "Could not find the main branch: origin/HEAD is not set and neither origin/main nor \
+         origin/master exist. Run 'git remote set-head origin -a' or set giti.mainBranch."
+            .to_string(),
+    ))
 }
 
 /// Parses git's configuration and extracts all aliases that do not shell out. Returns (key, value)
@@ -86,6 +138,61 @@ pub fn get_all_local_branches(repo: &git2::Repository) -> Result<HashMap<String,
     Ok(results)
 }
 
+/// Returns the remote that the upstream of the local 'branch' lives on, if it has an upstream.
+pub fn get_upstream_remote(repo: &git2::Repository, branch: &str) -> Option<String> {
+    repo.branch_upstream_remote(&format!("refs/heads/{}", branch))
+        .ok()
+        .and_then(|r| r.as_str().map(|s| s.to_string()))
+}
+
+/// Runs 'git fetch' for all 'remotes' at the same time.
+pub fn fetch_remotes<S: AsRef<str> + Sync>(remotes: &[S]) -> Result<()> {
+    std::thread::scope(|s| {
+        let handles: Vec<_> = remotes
+            .iter()
+            .map(|remote| s.spawn(move || run_command(&["git", "fetch", remote.as_ref()])))
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("git fetch thread panicked."))
+            .collect::<Result<Vec<_>>>()
+    })?;
+    Ok(())
+}
+
+/// Pushes the local 'branches' to their upstreams. Branches going to the same remote are pushed
+/// in one 'git push' and all remotes are pushed to at the same time.
+pub fn push_to_upstreams(branches: &[&str]) -> Result<()> {
+    let mut refspecs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for branch in branches {
+        let origin = match get_origin(branch) {
+            Some(o) => o,
+            None => continue,
+        };
+        refspecs.entry(origin.remote).or_default().push(format!(
+            "refs/heads/{}:refs/heads/{}",
+            branch, origin.branch
+        ));
+    }
+    std::thread::scope(|s| {
+        let handles: Vec<_> = refspecs
+            .iter()
+            .map(|(remote, specs)| {
+                s.spawn(move || {
+                    let mut args = vec!["git", "push", remote.as_str()];
+                    args.extend(specs.iter().map(|s| s.as_str()));
+                    run_command(&args)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("git push thread panicked."))
+            .collect::<Result<Vec<_>>>()
+    })?;
+    Ok(())
+}
+
 #[derive(Debug, PartialEq, Eq)]
 struct GitHubRepository<'a> {
     remote: &'a Remote,
@@ -93,13 +200,7 @@ struct GitHubRepository<'a> {
 
 impl<'a> GitHubRepository<'a> {
     fn owner_and_project(&self) -> &str {
-        const GITHUB_HTTPS: &str = "https://github.com/";
-        self.remote
-            .url
-            .trim_start_matches(GITHUB_HTTPS)
-            .rsplit(':')
-            .nth(0)
-            .unwrap()
+        self.remote.path()
     }
 
     pub fn owner(&self) -> &str {
@@ -124,22 +225,37 @@ struct GitLabRepository<'a> {
 }
 
 impl<'a> GitLabRepository<'a> {
+    /// The path of the project including all its groups, e.g. 'group/subgroup/project', for both ssh
+    /// and https URLs.
     fn project(&self) -> &str {
-        const GITLAB_HTTPS: &str = "https://gitlab.com/";
         self.remote
-            .url
-            .trim_start_matches(GITLAB_HTTPS)
-            .rsplit(':')
-            .nth(0)
-            .unwrap()
+            .path()
+            .trim_matches('/')
             .trim_end_matches(".git")
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+struct BitbucketRepository<'a> {
+    remote: &'a Remote,
+}
+
+impl<'a> BitbucketRepository<'a> {
+    pub fn repository(&self) -> bitbucket::RepoId {
+        let path = self.remote.path().trim_end_matches(".git");
+        let (workspace, slug) = path.split_once('/').unwrap_or((path, ""));
+        bitbucket::RepoId {
+            workspace: workspace.to_string(),
+            slug: slug.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum RepositoryType<'a> {
     GitLab(GitLabRepository<'a>),
     GitHub(GitHubRepository<'a>),
+    Bitbucket(BitbucketRepository<'a>),
     Unknown,
 }
 
@@ -156,20 +272,104 @@ impl Remote {
         self.url.rsplit('/').nth(0).unwrap()
     }
 
-    pub fn repository(&self) -> RepositoryType {
-        if self.url.contains("github.com") {
+    /// The host of the URL, i.e. 'github.com' for both git@github.com:SirVer/giti.git and
+    /// https://github.com/SirVer/giti.git.
+    fn host(&self) -> &str {
+        let host = match self.url.split_once("://") {
+            Some((_, rest)) => rest.split('/').next().unwrap(),
+            None => self.url.split(':').next().unwrap(),
+        };
+        let host = host.rsplit('@').next().unwrap();
+        host.split(':').next().unwrap()
+    }
+
+    /// The path of the URL on the host, i.e. 'SirVer/giti.git' for git@github.com:SirVer/giti.git.
+    fn path(&self) -> &str {
+        match self.url.split_once("://") {
+            Some((_, rest)) => rest.split_once('/').map(|(_, p)| p).unwrap_or(""),
+            None => self.url.split_once(':').map(|(_, p)| p).unwrap_or(""),
+        }
+    }
+
+    /// The URL of 'owner's fork of this repository on 'host'. It uses the same protocol as this
+    /// URL, since users that clone over https often have no ssh keys.
+    fn fork_url(&self, host: &str, owner: &str) -> String {
+        if self.url.starts_with("https://") || self.url.starts_with("http://") {
+            format!("https://{}/{}/{}", host, owner, self.project())
+        } else {
+            format!("git@{}:{}/{}", host, owner, self.project())
+        }
+    }
+
+    /// Returns true if 'other' points at the same repository, even if the URL is spelled
+    /// differently, e.g. over https instead of ssh.
+    fn same_repository(&self, other: &Remote, hosts: &KnownHosts) -> bool {
+        let lower = |r: github::RepoId| (r.owner.to_lowercase(), r.name.to_lowercase());
+        match (self.repository_on(hosts), other.repository_on(hosts)) {
+            (RepositoryType::GitHub(a), RepositoryType::GitHub(b)) => {
+                lower(a.repository()) == lower(b.repository())
+            }
+            (RepositoryType::GitLab(a), RepositoryType::GitLab(b)) => {
+                a.project().eq_ignore_ascii_case(b.project())
+            }
+            (RepositoryType::Bitbucket(a), RepositoryType::Bitbucket(b)) => {
+                a.repository() == b.repository()
+            }
+            _ => self.url.trim_end_matches(".git") == other.url.trim_end_matches(".git"),
+        }
+    }
+
+    pub fn repository(&self) -> RepositoryType<'_> {
+        self.repository_on(&KnownHosts::from_config())
+    }
+
+    fn repository_on(&self, hosts: &KnownHosts) -> RepositoryType<'_> {
+        let host = self.host();
+        if is_host(host, &hosts.github.0, &hosts.github.1) {
             RepositoryType::GitHub(GitHubRepository { remote: self })
-        } else if self.url.contains("gitlab.com") {
+        } else if is_host(host, &hosts.gitlab.0, &hosts.gitlab.1) {
             RepositoryType::GitLab(GitLabRepository { remote: self })
+        } else if is_host(host, &hosts.bitbucket.0, &hosts.bitbucket.1) {
+            RepositoryType::Bitbucket(BitbucketRepository { remote: self })
         } else {
             RepositoryType::Unknown
         }
     }
 }
 
+/// The hosts of GitHub, GitLab and Bitbucket, each as (hosts, aliases) for is_host.
+struct KnownHosts {
+    github: (Vec<String>, Vec<String>),
+    gitlab: (Vec<String>, Vec<String>),
+    bitbucket: (Vec<String>, Vec<String>),
+}
+
+impl KnownHosts {
+    /// Reads the hosts from the environment and git's config.
+    fn from_config() -> Self {
+        KnownHosts {
+            github: (github::hosts(), config::get_list("giti.github.hosts")),
+            gitlab: (gitlab::hosts(), config::get_list("giti.gitlab.hosts")),
+            bitbucket: (
+                vec!["bitbucket.org".to_string()],
+                config::get_list("giti.bitbucket.hosts"),
+            ),
+        }
+    }
+}
+
+/// Returns true if 'host' is one of 'hosts', one of their subdomains, or one of 'aliases'. Aliases
+/// are for hosts from ~/.ssh/config, e.g. 'github-work' for a second GitHub account.
+fn is_host(host: &str, hosts: &[String], aliases: &[String]) -> bool {
+    hosts
+        .iter()
+        .any(|h| host == h || host.ends_with(&format!(".{}", h)))
+        || aliases.iter().any(|a| a == host)
+}
+
 /// Returns a map from origin name to Remote.
 fn get_remotes() -> Result<HashMap<String, Remote>> {
-    let stdout = String::from_utf8(communicate(&["git", "remote", "-v"])?.stdout).unwrap();
+    let stdout = String::from_utf8(communicate_checked(&["git", "remote", "-v"])?.stdout).unwrap();
     let mut result = HashMap::new();
     for line in stdout.lines() {
         if line.contains("(push)") {
@@ -185,29 +385,106 @@ fn get_remotes() -> Result<HashMap<String, Remote>> {
     Ok(result)
 }
 
-/// Returns the deleted or modified files in the working directory. This shells out to git
-/// directly, because using `libgit2::Repository::statuses`() was very, very slow.
-pub fn status() -> Result<(HashSet<PathBuf>, HashSet<PathBuf>)> {
-    let mut deleted = HashSet::<PathBuf>::new();
-    let mut modified = HashSet::<PathBuf>::new();
+/// The changed files in the work tree and the index, as reported by 'git status'.
+#[derive(Debug, Default, PartialEq)]
+pub struct Status {
+    pub added: HashSet<PathBuf>,
+    pub deleted: HashSet<PathBuf>,
+    pub modified: HashSet<PathBuf>,
+    // Maps the new path to the path it was renamed or copied from.
+    pub renamed: HashMap<PathBuf, PathBuf>,
+    pub copied: HashMap<PathBuf, PathBuf>,
+    pub untracked: HashSet<PathBuf>,
+}
+
+impl Status {
+    /// Parses the output of 'git status --porcelain', in which every line is a two character
+    /// status for the index and the work tree followed by the path, e.g. 'R  old -> new'.
+    fn parse(porcelain: &str) -> Result<Self> {
+        let mut status = Status::default();
+        for line in porcelain.lines() {
+            if line.len() < 4 || !line.is_char_boundary(2) {
+                return Err(Error::general(format!(
+                    "Unknown status output from git: '{}'",
+                    line
+                )));
+            }
+            let (xy, path) = (&line[..2], &line[3..]);
+            let (index, work_tree) = (xy.as_bytes()[0], xy.as_bytes()[1]);
+            let unquote = |p: &str| PathBuf::from(p.trim_matches('"'));
+            match (index, work_tree) {
+                (b'?', b'?') => {
+                    status.untracked.insert(unquote(path));
+                }
+                (b'!', b'!') => (),
+                (b'R', _) | (b'C', _) => {
+                    let (from, to) = match path.split_once(" -> ") {
+                        Some(p) => p,
+                        None => {
+                            return Err(Error::general(format!(
+                                "Unknown status output from git: '{}'",
+                                line
+                            )))
+                        }
+                    };
+                    let map = if index == b'R' {
+                        &mut status.renamed
+                    } else {
+                        &mut status.copied
+                    };
+                    map.insert(unquote(to), unquote(from));
+                }
+                (b'D', _) | (_, b'D') => {
+                    status.deleted.insert(unquote(path));
+                }
+                (b'A', _) => {
+                    status.added.insert(unquote(path));
+                }
+                _ => {
+                    status.modified.insert(unquote(path));
+                }
+            }
+        }
+        Ok(status)
+    }
+
+    /// Returns true if nothing is changed, not counting untracked files.
+    pub fn is_clean(&self) -> bool {
+        self.added.is_empty()
+            && self.deleted.is_empty()
+            && self.modified.is_empty()
+            && self.renamed.is_empty()
+            && self.copied.is_empty()
+    }
+
+    /// Returns all changed paths, not counting untracked files, in sorted order.
+    pub fn changed_paths(&self) -> Vec<&Path> {
+        let mut paths: Vec<&Path> = self
+            .added
+            .iter()
+            .chain(self.deleted.iter())
+            .chain(self.modified.iter())
+            .chain(self.renamed.keys())
+            .chain(self.copied.keys())
+            .map(|p| p.as_path())
+            .collect();
+        paths.sort();
+        paths
+    }
+}
 
+/// Returns the status of the work tree, ignoring untracked files. This shells out to git
+/// directly, because using `libgit2::Repository::statuses`() was very, very slow.
+pub fn status() -> Result<Status> {
     let stdout =
         String::from_utf8(communicate(&["git", "status", "--porcelain", "-uno"])?.stdout).unwrap();
-    for line in stdout.lines() {
-        let entries = line.trim().splitn(2, ' ').collect::<Vec<_>>();
-        match entries[0] {
-            "M" => modified.insert(PathBuf::from(entries[1])),
-            "D" => deleted.insert(PathBuf::from(entries[1])),
-            _ => panic!("Unknow status output from git: '{}'", line),
-        };
-    }
-    Ok((deleted, modified))
+    Status::parse(&stdout)
 }
 
 /// Returns an error if the working directory is dirty.
-fn expect_working_directory_clean() -> Result<()> {
-    let (deleted, changed) = status()?;
-    if deleted.len() + changed.len() == 0 {
+pub fn expect_working_directory_clean() -> Result<()> {
+    let status = status()?;
+    if status.is_clean() {
         return Ok(());
     }
 
@@ -215,7 +492,7 @@ fn expect_working_directory_clean() -> Result<()> {
         "You cannot have pending changes for this command. Changed \
          files:\n\n",
     );
-    for s in deleted.union(&changed) {
+    for s in status.changed_paths() {
         error.push_str(&format!("  {}\n", s.to_string_lossy()));
     }
     error.push('\n');
@@ -231,28 +508,31 @@ pub fn get_current_branch(repo: &git2::Repository) -> String {
 #[derive(Debug)]
 struct OriginBranch {
     remote: String,
-    _branch: String,
+    branch: String,
 }
 
 fn get_origin(local_branch: &str) -> Option<OriginBranch> {
-    let remote = match communicate(&["git", "config", &format!("branch.{}.remote", local_branch)]) {
-        Ok(out) => str::from_utf8(&out.stdout).unwrap().trim().to_string(),
-        Err(_) => return None,
-    };
+    // git config fails if the key is not set.
+    let remote =
+        match communicate_checked(&["git", "config", &format!("branch.{}.remote", local_branch)]) {
+            Ok(out) => str::from_utf8(&out.stdout).unwrap().trim().to_string(),
+            Err(_) => return None,
+        };
 
-    let _branch = match communicate(&["git", "config", &format!("branch.{}.merge", local_branch)]) {
-        Ok(out) => str::from_utf8(&out.stdout)
-            .unwrap()
-            .trim()
-            .trim_start_matches("refs/heads/")
-            .to_string(),
-        Err(_) => return None,
-    };
+    let branch =
+        match communicate_checked(&["git", "config", &format!("branch.{}.merge", local_branch)]) {
+            Ok(out) => str::from_utf8(&out.stdout)
+                .unwrap()
+                .trim()
+                .trim_start_matches("refs/heads/")
+                .to_string(),
+            Err(_) => return None,
+        };
 
-    if remote.is_empty() || _branch.is_empty() {
+    if remote.is_empty() || branch.is_empty() {
         return None;
     }
-    Some(OriginBranch { remote, _branch })
+    Some(OriginBranch { remote, branch })
 }
 
 /// Returns the (added, deleted, modified) files between two treeishs, e.g. branch names.
@@ -296,266 +576,1664 @@ pub fn get_changed_files(
     Ok((added, deleted, modified))
 }
 
-fn run_clang_format(path: &Path) -> Result<()> {
-    dispatch_to(
-        "clang-format",
-        &[
-            "-i",
-            "-sort-includes",
-            "-style=file",
-            "-fallback-style=Google",
-            &path.to_string_lossy(),
-        ],
-    )?;
-    Ok(())
-}
+/// File in the root of the repository that configures giti for everyone working on it.
+const GITI_TOML_FILE: &str = ".giti.toml";
 
-fn run_buildifier(path: &Path) -> Result<()> {
-    dispatch_to("buildifier", &[&path.to_string_lossy()])?;
-    Ok(())
+/// The formatters that 'g fix' runs. They are configured in the [fix] table of .giti.toml, e.g.
+///
+/// ```toml
+/// [fix]
+/// "*.rs" = "rustfmt {path}"
+/// "*.py" = "black --quiet {path}"
+/// ```
+///
+/// Every key is a glob that is matched against the file name, or against the path relative to the
+/// repository root if it contains a '/'. The first matching entry wins. The value is the command,
+/// split on whitespace; '{path}' is replaced by the file, which is appended if there is no
+/// placeholder. A formatter that needs longer than FORMATTER_TIMEOUT can be given its own timeout
+/// in seconds with '"*.rs" = { command = "rustfmt {path}", timeout = 60 }'.
+#[derive(Debug, PartialEq)]
+struct FormatterConfig {
+    // (glob, formatter)
+    formatters: Vec<(String, Formatter)>,
 }
 
-pub fn handle_fix(args: &[&str], repo: &git2::Repository) -> Result<()> {
-    expect_working_directory_clean()?;
-
-    let main_branch = get_main_branch();
-    let other_branch = if args.len() == 2 {
-        args[1].to_string()
-    } else {
-        format!("origin/{}", main_branch)
-    };
+/// How long a formatter may run before 'g fix' kills it, e.g. clang-format can hang on malformed
+/// files.
+const FORMATTER_TIMEOUT: Duration = Duration::from_secs(30);
 
-    println!("Fixing modified files compared to {}", other_branch);
-    let (added, _, modified) = get_changed_files(repo, &other_branch, &get_current_branch(repo))?;
-
-    let workdir = repo.workdir().unwrap();
-    for path in added.union(&modified) {
-        if path.file_name().is_none() {
-            continue;
-        }
-        let file_name = path.file_name().unwrap().to_str().unwrap();
-        let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("");
-        let full_path = workdir.join(path);
+#[derive(Debug, PartialEq)]
+struct Formatter {
+    // The command template, see formatter_command.
+    command: String,
+    timeout: Duration,
+}
 
-        match (file_name, ext) {
-            (_, "h") | (_, "cc") | (_, "proto") => run_clang_format(&full_path)?,
-            ("BUILD", _) | (_, "BUILD") => run_buildifier(&full_path)?,
-            _ => (),
+impl Formatter {
+    fn new(command: &str) -> Self {
+        Formatter {
+            command: command.to_string(),
+            timeout: FORMATTER_TIMEOUT,
         }
     }
+}
 
-    let changed_files = status()?.1;
-    if !changed_files.is_empty() {
-        println!("Fixed files:\n");
-        for filename in changed_files {
-            println!("  {}", filename.to_string_lossy());
+impl Default for FormatterConfig {
+    /// What 'g fix' always did: clang-format for C++ and protobuf, buildifier for Bazel.
+    fn default() -> Self {
+        const CLANG_FORMAT: &str =
+            "clang-format -i -sort-includes -style=file -fallback-style=Google {path}";
+        const BUILDIFIER: &str = "buildifier {path}";
+        let formatters = [
+            ("*.h", CLANG_FORMAT),
+            ("*.cc", CLANG_FORMAT),
+            ("*.proto", CLANG_FORMAT),
+            ("BUILD", BUILDIFIER),
+            ("*.BUILD", BUILDIFIER),
+        ];
+        FormatterConfig {
+            formatters: formatters
+                .iter()
+                .map(|(g, c)| (g.to_string(), Formatter::new(c)))
+                .collect(),
         }
-        println!();
-        dispatch_to("git", &["commit", "-am", "Ran git fix."])?;
     }
-    Ok(())
 }
 
-pub async fn handle_cleanup(repo: &git2::Repository, dbase: &mut diffbase::Diffbase) -> Result<()> {
-    let current_branch = get_current_branch(repo);
-
-    for branch in get_all_local_branch_names(repo)? {
-        if branch == current_branch {
-            continue;
-        }
-
-        if branch.starts_with('|') {
-            run_command(&["git", "branch", "-D", &branch])?;
-            continue;
+impl FormatterConfig {
+    /// Reads .giti.toml in 'workdir'. Falls back to the defaults if it does not exist or has no
+    /// [fix] table.
+    fn load(workdir: &Path) -> Result<Self> {
+        match ::std::fs::read_to_string(workdir.join(GITI_TOML_FILE)) {
+            Ok(content) => Self::from_toml(&content),
+            Err(_) => Ok(Self::default()),
         }
+    }
 
-        if let Some(merge_request) = dbase.get_merge_request(&branch) {
-            let should_delete = match merge_request {
-                MergeRequest::GitHub(pr_id) => {
-                    let pr = github::get_pr(pr_id).await?;
-                    if pr.state == github::PullRequestState::Closed {
-                        Some((pr_id.to_string(), branch))
-                    } else {
-                        None
+    fn from_toml(content: &str) -> Result<Self> {
+        let table: toml::Table = content.parse()?;
+        let fix = match table.get("fix") {
+            None => return Ok(Self::default()),
+            Some(toml::Value::Table(fix)) => fix,
+            Some(_) => {
+                return Err(Error::general(format!(
+                    "[fix] in {} must be a table.",
+                    GITI_TOML_FILE
+                )))
+            }
+        };
+        let mut formatters = Vec::new();
+        for (glob, value) in fix {
+            let formatter = match value {
+                toml::Value::String(command) => Some(Formatter::new(command)),
+                toml::Value::Table(table) => match (table.get("command"), table.get("timeout")) {
+                    (Some(toml::Value::String(command)), None) => Some(Formatter::new(command)),
+                    (Some(toml::Value::String(command)), Some(toml::Value::Integer(secs)))
+                        if *secs > 0 =>
+                    {
+                        Some(Formatter {
+                            command: command.to_string(),
+                            timeout: Duration::from_secs(*secs as u64),
+                        })
                     }
+                    _ => None,
+                },
+                _ => None,
+            };
+            match formatter {
+                Some(formatter) if formatter.command.trim().is_empty() => {
+                    return Err(Error::general(format!(
+                        "The formatter for '{}' in {} has an empty command.",
+                        glob, GITI_TOML_FILE
+                    )))
                 }
-                MergeRequest::GitLab(mr_id) => {
-                    let gitlab = gitlab::GitLab::new().unwrap();
-                    let mr = gitlab.get_mr(&mr_id.project(), mr_id.number()).await?;
-                    match mr.state {
-                        gitlab::PullRequestState::Closed | gitlab::PullRequestState::Merged => {
-                            Some((mr.web_url, mr.source_branch))
-                        }
-                        gitlab::PullRequestState::Open => None,
-                    }
+                Some(formatter) => formatters.push((glob.to_string(), formatter)),
+                None => {
+                    return Err(Error::general(format!(
+                        "The formatter for '{}' in {} must be a string or a table with a \
+                         'command' and an optional positive 'timeout' in seconds.",
+                        glob, GITI_TOML_FILE
+                    )))
                 }
-            };
-
-            if let Some((pr_id, branch)) = should_delete {
-                let rev = repo.revparse_single(&branch)?;
-                println!(
-                    "{} is closed. Deleting the branch {} ({}).",
-                    pr_id,
-                    branch,
-                    rev.id()
-                );
-                run_command(&["git", "branch", "-D", &branch])?;
-                continue;
-            };
+            }
         }
+        Ok(FormatterConfig { formatters })
     }
 
-    // Delete branches that have been merged upstream.
-
-    Ok(())
+    /// Returns the formatter for 'path', which is relative to the repository root.
+    fn formatter_for(&self, path: &Path) -> Option<&Formatter> {
+        let file_name = path.file_name()?.to_string_lossy();
+        let relative_path = path.to_string_lossy();
+        self.formatters
+            .iter()
+            .find(|(glob, _)| {
+                if glob.contains('/') {
+                    matches_glob(glob, &relative_path)
+                } else {
+                    matches_glob(glob, &file_name)
+                }
+            })
+            .map(|(_, formatter)| formatter)
+    }
 }
 
-pub fn handle_review_push(repo: &git2::Repository) -> Result<()> {
-    // branch name will be user/branch_name.
-    let full_branch_name = get_current_branch(repo);
-    let (user, branch_name) = {
-        let mut it = full_branch_name.splitn(2, '/');
-        // Slice off the leading '|'
-        (&it.next().unwrap()[1..], it.next().unwrap())
-    };
-    run_command(&[
-        "git",
-        "push",
-        "--force",
-        user,
-        &format!("HEAD:{}", branch_name),
-    ])?;
-    Ok(())
+/// Expands the command 'template' of a formatter to the command line that formats 'path'.
+fn formatter_command(template: &str, path: &Path) -> Vec<String> {
+    let path = path.to_string_lossy();
+    let mut command: Vec<String> = template
+        .split_whitespace()
+        .map(|a| a.replace("{path}", &path))
+        .collect();
+    if !template.contains("{path}") {
+        command.push(path.to_string());
+    }
+    command
 }
 
-pub async fn handle_review(
-    args: &[&str],
-    repo: &git2::Repository,
-    dbase: &mut diffbase::Diffbase,
-) -> Result<()> {
-    let remotes = get_remotes()?;
-
-    let main_branch = get_main_branch();
-    let main_origin = get_origin(&main_branch).unwrap();
-    let main_remote = &remotes[&main_origin.remote];
-    let repo_id = match main_remote.repository() {
-        RepositoryType::GitHub(s) => s.repository(),
-        _ => {
-            return Err(Error::general(
-                "Cannot handle 'review' for anything but GitHub Repos currently.".to_string(),
-            ))
+pub fn handle_fix(args: &[&str], repo: &git2::Repository) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optflag(
+        "",
+        "no-verify",
+        "Skip the pre-commit and commit-msg hooks when committing the fixes.",
+    );
+    opts.optopt(
+        "m",
+        "message",
+        "Commit the fixes with this message. [Ran git fix.]",
+        "MESSAGE",
+    );
+    opts.optopt(
+        "",
+        "since",
+        "Fix the files changed since this commit, tag or other ref.",
+        "REF",
+    );
+    opts.optflag(
+        "",
+        "staged",
+        "Fix the staged files and stage the fixes instead of committing them.",
+    );
+    let usage = "Usage: g fix [options] [BRANCH]";
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) if m.free.len() > 1 => {
+            let brief = format!("Too many arguments.\n{}", usage);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+        Ok(m)
+            if [
+                !m.free.is_empty(),
+                m.opt_present("since"),
+                m.opt_present("staged"),
+            ]
+            .iter()
+            .filter(|given| **given)
+            .count()
+                > 1 =>
+        {
+            let brief = format!(
+                "Only one of BRANCH, --since and --staged can be given.\n{}",
+                usage
+            );
+            return Err(Error::general(opts.usage(&brief)));
+        }
+        Ok(m) => m,
+        Err(err) => {
+            let brief = format!("{}\n{}", err, usage);
+            return Err(Error::general(opts.usage(&brief)));
         }
     };
 
-    if args.len() == 1 {
-        let prs = github::find_assigned_prs(Some(&repo_id)).await?;
-        if prs.is_empty() {
-            println!("No reviews assigned in {}/{}.", repo_id.owner, repo_id.name);
-        } else {
-            for pr in &prs {
-                println!(
-                    "#{} by @{}: {} ({}:{})",
-                    pr.number, pr.author_login, pr.title, pr.source.repo.owner, pr.source.name
-                );
-            }
+    let staged = matches.opt_present("staged");
+    let paths: BTreeSet<PathBuf> = if staged {
+        let paths = diff_names(&["--cached", "--diff-filter=ACMR"])?;
+        // Staging the fixes would also stage these changes.
+        let partially_staged: Vec<String> = paths
+            .intersection(&diff_names(&[])?)
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect();
+        if !partially_staged.is_empty() {
+            return Err(Error::general(format!(
+                "These files also have unstaged changes, stage or stash them first: {}",
+                partially_staged.join(", ")
+            )));
         }
-        return Ok(());
-    }
-
-    if args.len() != 2 {
-        return Err(Error::general(
-            "review requires a pull request number or a user/branch_name to review.".into(),
-        ));
-    }
-
-    expect_working_directory_clean()?;
-
-    if args[1] == "push" {
-        return handle_review_push(repo);
-    }
-
-    let (source_branch, merge_request) = if let Ok(pr_number) = args[1].parse::<i32>() {
-        let pr = github::get_pr(&github::PullRequestId {
-            repo: repo_id.clone(),
-            number: pr_number,
-        })
-        .await?;
-        let merge_request = MergeRequest::GitHub(pr.id());
-        (pr.source, Some(merge_request))
+        println!("Fixing staged files");
+        paths
     } else {
-        let (user, branch) = {
-            let mut it = args[1].splitn(2, ':');
-            (it.next().unwrap(), it.next().unwrap())
-        };
+        expect_working_directory_clean()?;
 
-        let branch = github::Branch {
-            repo: github::RepoId {
-                owner: user.to_string(),
-                name: repo_id.name.clone(),
-            },
-            name: branch.to_string(),
+        let other_branch = match (matches.opt_str("since"), matches.free.first()) {
+            (Some(since), _) => since,
+            (None, Some(branch)) => branch.to_string(),
+            (None, None) => format!("origin/{}", get_main_branch()?),
         };
-        (branch, None)
-    };
 
-    let owner = if source_branch.repo == repo_id {
-        "origin"
-    } else {
-        &source_branch.repo.owner
+        println!("Fixing modified files compared to {}", other_branch);
+        let (added, _, modified) =
+            get_changed_files(repo, &other_branch, &get_current_branch(repo))?;
+        added.union(&modified).cloned().collect()
     };
 
-    if !remotes.contains_key(owner) {
-        run_command(&[
-            "git",
-            "remote",
-            "add",
-            owner,
-            &format!("git@github.com:{}/{}", owner, main_remote.project()),
-        ])?;
+    let workdir = repo.workdir().unwrap();
+    let formatters = FormatterConfig::load(workdir)?;
+    let mut fixed = Vec::new();
+    for path in &paths {
+        let formatter = match formatters.formatter_for(path) {
+            None => continue,
+            Some(f) => f,
+        };
+        // The paths are relative to the root of the repository, not to the current directory.
+        let path = workdir.join(path);
+        let command = formatter_command(&formatter.command, &path);
+        let args: Vec<&str> = command[1..].iter().map(|a| a as &str).collect();
+        dispatch_to_with_timeout(&command[0], &args, formatter.timeout)?;
+        fixed.push(path.to_string_lossy().into_owned());
     }
-    // Since the local_branch name is the remote/branch git also resolves it to the correct remote.
-    run_command(&["git", "fetch", owner])?;
-    let branch_to_fork = format!("remotes/{}/{}", owner, source_branch.name);
-    let local_branch = format!("|{}/{}", owner, source_branch.name);
 
-    if get_all_local_branch_names(repo)?.contains(&local_branch) {
-        run_command(&["git", "branch", "-D", &local_branch])?;
+    if staged {
+        if !fixed.is_empty() {
+            let mut add_args = vec!["add", "--"];
+            add_args.extend(fixed.iter().map(|p| p as &str));
+            dispatch_to("git", &add_args)?;
+        }
+        return Ok(());
     }
 
-    run_command(&["git", "branch", "--track", &local_branch, &branch_to_fork])?;
-    if let Some(merge_request) = merge_request {
-        dbase.set_merge_request(&local_branch, merge_request);
+    let status = status()?;
+    if !status.modified.is_empty() {
+        println!("Fixed files:\n");
+        for filename in &status.modified {
+            println!("  {}", filename.to_string_lossy());
+        }
+        println!();
+        let message = matches
+            .opt_str("message")
+            .unwrap_or_else(|| "Ran git fix.".to_string());
+        let mut commit_args = vec!["commit", "-am", &message];
+        if matches.opt_present("no-verify") {
+            commit_args.push("--no-verify");
+        }
+        dispatch_to("git", &commit_args)?;
     }
-    checkout(repo, &local_branch)?;
     Ok(())
 }
 
-pub fn checkout(repo: &git2::Repository, branch: &str) -> Result<()> {
-    run_command(&["git", "checkout", branch])?;
-    if !repo.submodules().unwrap().is_empty() {
-        run_command(&["git", "submodule", "update", "--init", "--recursive"])?;
+/// Returns the paths that 'git diff --name-only' with 'args' lists, relative to the root of the
+/// repository.
+fn diff_names(args: &[&str]) -> Result<BTreeSet<PathBuf>> {
+    let mut command = vec!["git", "diff", "--name-only"];
+    command.extend(args);
+    let output = communicate_checked(&command)?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Returns the diffbase of 'branch', or origin/main if it has none.
+fn diffbase_or_main(dbase: &diffbase::Diffbase, branch: &str) -> Result<String> {
+    match dbase.get_parent(branch) {
+        Some(parent) => Ok(parent.to_string()),
+        None => Ok(format!("origin/{}", get_main_branch()?)),
     }
-    Ok(())
 }
 
-pub fn handle_clone(args: &[&str]) -> Result<()> {
-    let github_repo_regex =
-        regex::Regex::new(r"^[a-zA-Z\d][a-zA-Z\d-]*/[a-zA-Z\d][a-zA-Z\d-]").unwrap();
+/// Returns where HEAD branched off 'other_branch'.
+fn merge_base_with(repo: &git2::Repository, other_branch: &str) -> Result<git2::Oid> {
+    let head = repo.head()?.peel_to_commit()?.id();
+    let other = repo.revparse_single(other_branch)?.peel_to_commit()?.id();
+    Ok(repo.merge_base(head, other)?)
+}
 
-    let new_args: Vec<_> = args
-        .iter()
-        .map(|a| {
+/// Shows the uncommitted changes and what the branch changed compared to its diffbase, or to
+/// origin/main if it has none.
+pub fn handle_uncommitted(repo: &git2::Repository, dbase: &diffbase::Diffbase) -> Result<()> {
+    let current_branch = get_current_branch(repo);
+    let other_branch = diffbase_or_main(dbase, &current_branch)?;
+    let status = status()?;
+    let (added, _, modified) = get_changed_files(repo, &other_branch, &current_branch)?;
+
+    fn print_section<'a>(title: &str, paths: impl Iterator<Item = &'a Path>) {
+        let mut paths: Vec<_> = paths.collect();
+        paths.sort();
+        println!("{}:", title);
+        if paths.is_empty() {
+            println!("  (none)");
+        }
+        for path in paths {
+            println!("  {}", path.to_string_lossy());
+        }
+        println!();
+    }
+    print_section("Uncommitted", status.changed_paths().into_iter());
+    print_section(
+        &format!("Added compared to {}", other_branch),
+        added.iter().map(|p| p.as_path()),
+    );
+    print_section(
+        &format!("Modified compared to {}", other_branch),
+        modified.iter().map(|p| p.as_path()),
+    );
+    Ok(())
+}
+
+/// Squashes the commits of the current branch on top of its diffbase, or origin/main if it has none,
+/// into a single commit. The message is written in the editor, which starts out with the subjects
+/// of the squashed commits.
+pub fn handle_squash(repo: &git2::Repository, dbase: &diffbase::Diffbase) -> Result<()> {
+    expect_working_directory_clean()?;
+
+    let current_branch = get_current_branch(repo);
+    let other_branch = diffbase_or_main(dbase, &current_branch)?;
+    let base = merge_base_with(repo, &other_branch)?;
+
+    let subjects: Vec<String> = branch_commits(repo, base)?
+        .into_iter()
+        .map(|(_, summary)| summary)
+        .collect();
+    if subjects.is_empty() {
+        return Err(Error::general(format!(
+            "{} has no commits on top of {} to squash.",
+            current_branch, other_branch
+        )));
+    }
+
+    let mut temp_file = tempfile::Builder::new()
+        .prefix("COMMIT_EDITMSG")
+        .rand_bytes(0)
+        .tempfile()?;
+    temp_file.write_all(subjects.join("\n\n").as_bytes())?;
+    let temp_path = temp_file.into_temp_path();
+    run_editor(&temp_path)?;
+    let message = ::std::fs::read_to_string(&temp_path)?;
+    if message.trim().is_empty() {
+        return Err(Error::general(
+            "Not squashing because the commit message is empty.".to_string(),
+        ));
+    }
+
+    println!(
+        "Squashing {} commits on top of {}.",
+        subjects.len(),
+        other_branch
+    );
+    run_command(&["git", "reset", "--soft", &base.to_string()])?;
+    run_command(&["git", "commit", "-F", temp_path.to_str().unwrap()])
+}
+
+/// Commits the changes in the work tree as a fixup of a commit on the current branch, which is
+/// picked from a list. With --rebase, squashes the existing fixups into their commits instead.
+pub fn handle_fixup(
+    args: &[&str],
+    repo: &git2::Repository,
+    dbase: &diffbase::Diffbase,
+) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optflag(
+        "",
+        "rebase",
+        "Run 'git rebase -i --autosquash' onto the diffbase to squash the fixups.",
+    );
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(err) => {
+            let brief = format!("{}\nUsage: g fixup [options]", err);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+    };
+
+    let current_branch = get_current_branch(repo);
+    let other_branch = diffbase_or_main(dbase, &current_branch)?;
+    if matches.opt_present("rebase") {
+        return run_command(&["git", "rebase", "-i", "--autosquash", &other_branch]);
+    }
+
+    let base = merge_base_with(repo, &other_branch)?;
+    let mut commits = branch_commits(repo, base)?;
+    if commits.is_empty() {
+        return Err(Error::general(format!(
+            "{} has no commits on top of {} to fix up.",
+            current_branch, other_branch
+        )));
+    }
+    // The latest commit is the most likely target, so it comes first.
+    commits.reverse();
+    let items: Vec<String> = commits
+        .iter()
+        .map(|(oid, summary)| format!("{:.7} {}", oid.to_string(), summary))
+        .collect();
+    let items: Vec<&str> = items.iter().map(|i| i as &str).collect();
+    let index = prompt::pick("Fix up which commit?", &items)?;
+    let fixup = format!("--fixup={}", commits[index].0);
+    run_command(&["git", "commit", "-a", &fixup])
+}
+
+/// Amends the tip commit with all changes in the work tree and force pushes it if the branch has an
+/// upstream.
+pub fn handle_amend(
+    args: &[&str],
+    repo: &git2::Repository,
+    dbase: &diffbase::Diffbase,
+) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optflag("e", "edit", "Edit the commit message.");
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(err) => {
+            let brief = format!("{}\nUsage: g amend [options]", err);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+    };
+
+    if repo.index()?.has_conflicts() {
+        return Err(Error::general(
+            "There are unmerged files. Resolve the conflicts before amending.".to_string(),
+        ));
+    }
+    let edit = if matches.opt_present("edit") {
+        "--edit"
+    } else {
+        "--no-edit"
+    };
+    run_command(&["git", "commit", "--amend", "-a", edit])?;
+
+    let current_branch = get_current_branch(repo);
+    if get_origin(&current_branch).is_none() {
+        return Ok(());
+    }
+    run_command(&["git", "push", "--force-with-lease"])?;
+    if let Some(merge_request) = dbase.get_merge_request(&current_branch) {
+        println!("Updated {}", merge_request.url());
+    }
+    Ok(())
+}
+
+/// Returns the commits between 'base' and HEAD with their summary, oldest first. Merges are left
+/// out, since they are just the diffbase being merged in.
+fn branch_commits(repo: &git2::Repository, base: git2::Oid) -> Result<Vec<(git2::Oid, String)>> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    revwalk.push_head()?;
+    revwalk.hide(base)?;
+    let mut commits = Vec::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        if commit.parent_count() > 1 {
+            continue;
+        }
+        let summary = commit.summary().unwrap_or_default().to_string();
+        commits.push((commit.id(), summary));
+    }
+    Ok(commits)
+}
+
+/// Returns true if 'name' matches the shell-style 'pattern', which supports '*' and '?'.
+fn matches_glob(pattern: &str, name: &str) -> bool {
+    let mut regex = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex::Regex::new(&regex).unwrap().is_match(name)
+}
+
+pub async fn handle_cleanup(
+    args: &[&str],
+    repo: &git2::Repository,
+    dbase: &mut diffbase::Diffbase,
+) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optmulti(
+        "k",
+        "keep",
+        "Never delete branches matching this glob. Can be repeated. Also read from giti.cleanup.keep.",
+        "PATTERN",
+    );
+    opts.optflag(
+        "n",
+        "dry-run",
+        "Only print the branches that would be deleted.",
+    );
+    opts.optflag(
+        "",
+        "keep-unmerged",
+        "Keep branches whose PR was closed without being merged.",
+    );
+    opts.optflag(
+        "",
+        "prune-remotes",
+        "Offer to remove the remotes, e.g. of forks, that only deleted branches tracked.",
+    );
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(err) => {
+            let brief = format!("{}\nUsage: g cleanup [options]", err);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+    };
+    let mut keep_patterns = matches.opt_strs("keep");
+    keep_patterns.extend(config::get_list("giti.cleanup.keep"));
+    let protected = protected_branches(repo);
+
+    let current_branch = get_current_branch(repo);
+    let main_branch = get_main_branch()?;
+    let mut journal = read_cleanup_journal(repo);
+    let local_branches = get_all_local_branches(repo)?;
+    // Looked up before any branch is deleted, since that removes its config.
+    let tracked_remotes: HashMap<String, String> = local_branches
+        .keys()
+        .filter_map(|b| Some((b.clone(), get_upstream_remote(repo, b)?)))
+        .collect();
+
+    let (skipped, branches): (Vec<String>, Vec<String>) = local_branches
+        .keys()
+        .filter(|b| **b != current_branch && **b != main_branch)
+        .cloned()
+        .partition(|b| protected.contains(b) || keep_patterns.iter().any(|p| matches_glob(p, b)));
+    if !skipped.is_empty() {
+        println!("Skipping protected branches: {}.", skipped.join(", "));
+    }
+    let closed =
+        find_closed_merge_requests(dbase, &branches, matches.opt_present("keep-unmerged")).await?;
+    let dry_run = matches.opt_present("dry-run");
+
+    let mut remaining = Vec::new();
+    // The branches that are deleted, or would be with --dry-run.
+    let mut deleted = Vec::new();
+    for branch in branches {
+        if branch.starts_with('|') {
+            if dry_run {
+                let rev = repo.revparse_single(&branch)?;
+                println!("Would delete the branch {} ({}).", branch, rev.id());
+            } else {
+                delete_branch(repo, &branch, &mut journal)?;
+            }
+            deleted.push(branch);
+            continue;
+        }
+
+        if let Some(pr_id) = closed.get(&branch) {
+            let rev = repo.revparse_single(&branch)?;
+            if dry_run {
+                println!(
+                    "{} is closed. Would delete the branch {} ({}).",
+                    pr_id,
+                    branch,
+                    rev.id()
+                );
+                deleted.push(branch);
+                continue;
+            }
+            println!(
+                "{} is closed. Deleting the branch {} ({}).",
+                pr_id,
+                branch,
+                rev.id()
+            );
+            delete_branch(repo, &branch, &mut journal)?;
+            deleted.push(branch);
+            continue;
+        }
+        remaining.push(branch);
+    }
+
+    // Delete branches that have been merged upstream, even without a PR recorded in the diffbase.
+    if let Some(main_tip) = main_branch_tip(repo, &main_branch) {
+        let mut merged = Vec::new();
+        for branch in remaining {
+            if local_branches[&branch].upstream.is_none() {
+                continue;
+            }
+            let tip = repo.revparse_single(&branch)?.id();
+            if has_merged_commits(repo, tip, main_tip)? {
+                merged.push((branch, tip));
+            }
+        }
+        // Branches that others are still based on stay, so that the stack is not torn apart.
+        loop {
+            let gone: HashSet<String> = deleted
+                .iter()
+                .chain(merged.iter().map(|(branch, _)| branch))
+                .cloned()
+                .collect();
+            let before = merged.len();
+            merged.retain(|(branch, _)| {
+                dbase
+                    .get_children(branch)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .all(|child| !local_branches.contains_key(child) || gone.contains(child))
+            });
+            if merged.len() == before {
+                break;
+            }
+        }
+
+        for (branch, tip) in merged {
+            if dry_run {
+                println!(
+                    "{} is merged into {}. Would delete the branch ({}).",
+                    branch, main_branch, tip
+                );
+                deleted.push(branch);
+                continue;
+            }
+            println!(
+                "{} is merged into {}. Deleting the branch ({}).",
+                branch, main_branch, tip
+            );
+            delete_branch(repo, &branch, &mut journal)?;
+            deleted.push(branch);
+        }
+    }
+    // The remote-tracking branches of deleted branches would otherwise linger in 'git branch -r'.
+    // Those that the main branch or any other remaining branch still track are kept.
+    let in_use: HashSet<&String> = local_branches
+        .iter()
+        .filter(|(branch, _)| !deleted.contains(branch))
+        .filter_map(|(_, info)| info.upstream.as_ref())
+        .collect();
+    let stale: BTreeSet<&String> = deleted
+        .iter()
+        .filter_map(|branch| local_branches[branch].upstream.as_ref())
+        .filter(|upstream| {
+            !in_use.contains(upstream)
+                && repo
+                    .find_reference(&format!("refs/remotes/{}", upstream))
+                    .is_ok()
+        })
+        .collect();
+    for upstream in stale {
+        if dry_run {
+            println!("Would delete the remote-tracking branch {}.", upstream);
+            continue;
+        }
+        if let Err(err) = run_command(&["git", "branch", "--delete", "--remotes", upstream]) {
+            println!(
+                "Could not delete the remote-tracking branch {}: {}",
+                upstream, err
+            );
+        }
+    }
+
+    if matches.opt_present("prune-remotes") {
+        prune_remotes(&tracked_remotes, &deleted, &main_branch, dry_run)?;
+    }
+    Ok(())
+}
+
+/// Offers to remove the remotes that only 'deleted' branches tracked, e.g. the forks that
+/// 'g review' added. 'tracked_remotes' maps every local branch to the remote it tracks. origin
+/// and the remote of the main branch are never removed.
+fn prune_remotes(
+    tracked_remotes: &HashMap<String, String>,
+    deleted: &[String],
+    main_branch: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let main_remote = get_origin(main_branch).map(|o| o.remote);
+    let in_use: HashSet<&String> = tracked_remotes
+        .iter()
+        .filter(|(branch, _)| !deleted.contains(branch))
+        .map(|(_, remote)| remote)
+        .collect();
+    let unused: BTreeSet<&String> = deleted
+        .iter()
+        .filter_map(|branch| tracked_remotes.get(branch))
+        .filter(|remote| {
+            *remote != "origin" && Some(*remote) != main_remote.as_ref() && !in_use.contains(remote)
+        })
+        .collect();
+    for remote in unused {
+        if dry_run {
+            println!("Would offer to remove the remote {}.", remote);
+            continue;
+        }
+        let question = format!("No branch tracks the remote {} anymore. Remove it?", remote);
+        if prompt::confirm(&question, false)? {
+            run_command(&["git", "remote", "remove", remote])?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the commit the main branch points to, preferring origin's view of it since the local
+/// main branch is often outdated.
+fn main_branch_tip(repo: &git2::Repository, main_branch: &str) -> Option<git2::Oid> {
+    repo.revparse_single(&format!("origin/{}", main_branch))
+        .or_else(|_| repo.revparse_single(main_branch))
+        .ok()
+        .map(|o| o.id())
+}
+
+/// Returns true if a branch pointing to 'tip' has commits of its own that are now contained in
+/// 'main', i.e. it has been merged. A branch that was only started off main has none, its tip is on
+/// main's first-parent history.
+fn has_merged_commits(repo: &git2::Repository, tip: git2::Oid, main: git2::Oid) -> Result<bool> {
+    if tip == main || !repo.graph_descendant_of(main, tip)? {
+        return Ok(false);
+    }
+    // Walk main's first parents down to the commits that 'tip' contains. If 'tip' itself comes
+    // next, main went through it.
+    let mut revwalk = repo.revwalk()?;
+    revwalk.simplify_first_parent()?;
+    revwalk.push(main)?;
+    revwalk.hide(tip)?;
+    let mut oldest = main;
+    for oid in revwalk {
+        oldest = oid?;
+    }
+    Ok(repo.find_commit(oldest)?.parent_ids().next() != Some(tip))
+}
+
+/// Lists branches that 'g cleanup' must never delete, one per line. Lines starting with '#' are
+/// comments.
+const GITI_KEEP_FILE: &str = ".giti-keep";
+
+/// Returns the names of the branches that 'g cleanup' must never delete. They are read from
+/// giti.cleanup.protect and from .giti-keep in the root of the repository.
+fn protected_branches(repo: &git2::Repository) -> HashSet<String> {
+    let mut protected: HashSet<String> = config::get_list("giti.cleanup.protect")
+        .into_iter()
+        .collect();
+    if let Some(workdir) = repo.workdir() {
+        if let Ok(content) = ::std::fs::read_to_string(workdir.join(GITI_KEEP_FILE)) {
+            protected.extend(
+                content
+                    .lines()
+                    .map(|l| l.trim())
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(|l| l.to_string()),
+            );
+        }
+    }
+    protected
+}
+
+/// Returns those of 'branches' whose pull request is closed or merged, mapped to the id of the pull
+/// request. With 'merged_only', pull requests that were closed without being merged are skipped.
+/// All pull requests are fetched at once, so that cleaning up many branches does not run into rate
+/// limits. Branches starting with '|' are not checked, cleanup deletes them anyways.
+async fn find_closed_merge_requests(
+    dbase: &diffbase::Diffbase,
+    branches: &[String],
+    merged_only: bool,
+) -> Result<HashMap<String, String>> {
+    let mut github_ids = Vec::new();
+    let mut gitlab_ids = Vec::new();
+    let mut bitbucket_ids = Vec::new();
+    for branch in branches.iter().filter(|b| !b.starts_with('|')) {
+        match dbase.get_merge_request(branch) {
+            None => (),
+            Some(MergeRequest::GitHub(pr_id)) => github_ids.push((branch, pr_id.clone())),
+            Some(MergeRequest::GitLab(mr_id)) => gitlab_ids.push((branch, mr_id)),
+            Some(MergeRequest::Bitbucket(pr_id)) => bitbucket_ids.push((branch, pr_id)),
+        }
+    }
+
+    let mut closed = HashMap::new();
+    if !github_ids.is_empty() {
+        let pr_ids: Vec<_> = github_ids.iter().map(|(_, id)| id.clone()).collect();
+        let prs = github::get_prs(&pr_ids).await?;
+        for ((branch, pr_id), pr) in github_ids.into_iter().zip(prs) {
+            match pr.state {
+                github::PullRequestState::Merged => {
+                    closed.insert(branch.to_string(), pr_id.to_string());
+                }
+                github::PullRequestState::Closed if !merged_only => {
+                    closed.insert(branch.to_string(), pr_id.to_string());
+                }
+                github::PullRequestState::Closed | github::PullRequestState::Open => (),
+            }
+        }
+    }
+    if !gitlab_ids.is_empty() {
+        let gitlab = &gitlab::GitLab::new()?;
+        let mrs = join_all(
+            gitlab_ids
+                .iter()
+                .map(|(_, id)| async move { gitlab.get_mr(&id.project(), id.number()).await }),
+        )
+        .await;
+        for ((branch, _), mr) in gitlab_ids.into_iter().zip(mrs) {
+            let mr = mr?;
+            match mr.state {
+                gitlab::PullRequestState::Merged => {
+                    closed.insert(branch.to_string(), mr.web_url);
+                }
+                gitlab::PullRequestState::Closed if !merged_only => {
+                    closed.insert(branch.to_string(), mr.web_url);
+                }
+                gitlab::PullRequestState::Closed | gitlab::PullRequestState::Open => (),
+            }
+        }
+    }
+    if !bitbucket_ids.is_empty() {
+        let bitbucket = bitbucket::Bitbucket::new()?;
+        let prs = join_all(bitbucket_ids.iter().map(|(_, id)| bitbucket.get_pr(id))).await;
+        for ((branch, _), pr) in bitbucket_ids.into_iter().zip(prs) {
+            let pr = pr?;
+            let delete = match pr.state {
+                bitbucket::PullRequestState::Open => false,
+                bitbucket::PullRequestState::Merged => true,
+                bitbucket::PullRequestState::Declined | bitbucket::PullRequestState::Superseded => {
+                    !merged_only
+                }
+            };
+            if delete {
+                closed.insert(branch.to_string(), pr.web_url);
+            }
+        }
+    }
+    Ok(closed)
+}
+
+/// Restores the branches that 'g cleanup' deleted within the retention period.
+pub fn handle_undo_cleanup(repo: &git2::Repository) -> Result<()> {
+    let local_branches = get_all_local_branch_names(repo)?;
+    let (restorable, mut journal): (Vec<_>, Vec<_>) = read_cleanup_journal(repo)
+        .into_iter()
+        .partition(|d| !local_branches.contains(&d.branch));
+    if restorable.is_empty() {
+        println!("No branches deleted by 'g cleanup' to restore.");
+        return Ok(());
+    }
+
+    println!("Would restore:");
+    for deleted in &restorable {
+        let deleted_at = Local
+            .timestamp_opt(deleted.deleted_at, 0)
+            .single()
+            .map(|d| d.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        println!(
+            "  {} at {} (deleted {})",
+            deleted.branch, deleted.sha, deleted_at
+        );
+    }
+    if !prompt::confirm("Restore these branches?", false)? {
+        return Ok(());
+    }
+
+    for deleted in restorable {
+        if let Err(err) = run_command(&["git", "branch", &deleted.branch, &deleted.sha]) {
+            // Keep it around, so that a later attempt can try again.
+            println!("Could not restore {}: {}", deleted.branch, err);
+            journal.push(deleted);
+        }
+    }
+    write_cleanup_journal(repo, &journal)
+}
+
+/// File in the git directory that records the branches deleted by 'g cleanup'.
+const CLEANUP_JOURNAL_FILE: &str = "giti_cleanup_journal.json";
+
+/// How long 'g undo-cleanup' can restore a branch. Matches the default of git's gc.pruneExpire,
+/// after which the commits of a deleted branch might be gone.
+const CLEANUP_RETENTION_DAYS: i64 = 14;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DeletedBranch {
+    branch: String,
+    sha: String,
+    // Unix timestamp.
+    deleted_at: i64,
+}
+
+/// Returns the journal of deleted branches without the ones older than the retention period.
+fn read_cleanup_journal(repo: &git2::Repository) -> Vec<DeletedBranch> {
+    let oldest = Local::now().timestamp() - CLEANUP_RETENTION_DAYS * 24 * 60 * 60;
+    let journal: Vec<DeletedBranch> =
+        ::std::fs::read_to_string(repo.path().join(CLEANUP_JOURNAL_FILE))
+            .ok()
+            .and_then(|c| serde_json::from_str(&c).ok())
+            .unwrap_or_default();
+    journal
+        .into_iter()
+        .filter(|d| d.deleted_at >= oldest)
+        .collect()
+}
+
+fn write_cleanup_journal(repo: &git2::Repository, journal: &[DeletedBranch]) -> Result<()> {
+    ::std::fs::write(
+        repo.path().join(CLEANUP_JOURNAL_FILE),
+        serde_json::to_string_pretty(journal)?,
+    )?;
+    Ok(())
+}
+
+/// Force deletes 'branch' and records its tip in 'journal', so that it can be restored. The journal
+/// is written right away, so that an error later on does not lose the branches deleted so far.
+fn delete_branch(
+    repo: &git2::Repository,
+    branch: &str,
+    journal: &mut Vec<DeletedBranch>,
+) -> Result<()> {
+    let sha = repo.revparse_single(branch)?.id().to_string();
+    run_command(&["git", "branch", "-D", branch])?;
+    journal.push(DeletedBranch {
+        branch: branch.to_string(),
+        sha,
+        deleted_at: Local::now().timestamp(),
+    });
+    write_cleanup_journal(repo, journal)
+}
+
+pub fn handle_review_push(repo: &git2::Repository) -> Result<()> {
+    // branch name will be user/branch_name.
+    let full_branch_name = get_current_branch(repo);
+    let (user, branch_name) = {
+        let mut it = full_branch_name.splitn(2, '/');
+        // Slice off the leading '|'
+        (&it.next().unwrap()[1..], it.next().unwrap())
+    };
+    run_command(&[
+        "git",
+        "push",
+        "--force",
+        user,
+        &format!("HEAD:{}", branch_name),
+    ])?;
+    Ok(())
+}
+
+/// Prints the output of 'get_changed_files', either grouped by status or as a sorted list of
+/// paths.
+fn print_changed_files(
+    added: &HashSet<PathBuf>,
+    deleted: &HashSet<PathBuf>,
+    modified: &HashSet<PathBuf>,
+    name_only: bool,
+) {
+    if name_only {
+        let mut all: Vec<_> = added.union(deleted).chain(modified.iter()).collect();
+        all.sort();
+        for path in all {
+            println!("{}", path.to_string_lossy());
+        }
+        return;
+    }
+
+    for (title, paths) in &[
+        ("Added", added),
+        ("Deleted", deleted),
+        ("Modified", modified),
+    ] {
+        if paths.is_empty() {
+            continue;
+        }
+        let mut paths: Vec<_> = paths.iter().collect();
+        paths.sort();
+        println!("{}:", title);
+        for path in paths {
+            println!("  {}", path.to_string_lossy());
+        }
+    }
+}
+
+async fn print_assigned_prs(repo_id: &github::RepoId) -> Result<()> {
+    let prs = github::find_assigned_prs(Some(repo_id)).await?;
+    if prs.is_empty() {
+        println!("No reviews assigned in {}/{}.", repo_id.owner, repo_id.name);
+    } else {
+        for pr in &prs {
+            let stats = pr
+                .diff_stats()
+                .map(|s| format!(" ({})", s))
+                .unwrap_or_default();
+            println!(
+                "#{} by @{}: {} ({}:{}){}",
+                pr.number, pr.author_login, pr.title, pr.source.repo.owner, pr.source.name, stats
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Prints the open PRs of the user that wait for them, because a reviewer requested changes or the
+/// checks fail.
+async fn print_blocked_prs(repo_id: &github::RepoId) -> Result<()> {
+    let prs = github::find_open_authored_prs(repo_id).await?;
+    let states = join_all(prs.iter().map(|pr| async move {
+        let id = pr.id();
+        try_join!(
+            github::has_requested_changes(&id),
+            github::get_pr_checks(&id, &pr.head_sha)
+        )
+    }))
+    .await;
+
+    let mut found = false;
+    for (pr, state) in prs.iter().zip(states) {
+        let (changes_requested, checks) = state?;
+        let mut reasons = Vec::new();
+        if changes_requested {
+            reasons.push("changes requested");
+        }
+        if checks == github::ChecksState::Failing {
+            reasons.push("checks failing");
+        }
+        if reasons.is_empty() {
+            continue;
+        }
+        found = true;
+        println!("#{}: {} ({})", pr.number, pr.title, reasons.join(", "));
+    }
+    if !found {
+        println!(
+            "None of your open PRs in {}/{} need your action.",
+            repo_id.owner, repo_id.name
+        );
+    }
+    Ok(())
+}
+
+async fn print_assigned_mrs(project: &str) -> Result<()> {
+    let mrs: Vec<_> = gitlab::GitLab::new()?
+        .find_assigned_mrs()
+        .await?
+        .into_iter()
+        .filter(|mr| mr.id().project() == project)
+        .collect();
+    if mrs.is_empty() {
+        println!("No reviews assigned in {}.", project);
+    } else {
+        for mr in &mrs {
+            println!(
+                "!{} by @{}: {} ({})",
+                mr.number, mr.author.username, mr.title, mr.source_branch
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Where the branch that 'g review' checks out comes from.
+struct ReviewSource {
+    // The name of the remote that has the branch. It is added with 'url' if it does not exist.
+    remote: String,
+    url: String,
+    branch: String,
+    // The branch the changes are meant to be merged into.
+    target_branch: String,
+    merge_request: Option<MergeRequest>,
+}
+
+/// Returns the name of the remote to fetch the branch under review from. A remote that already
+/// points at 'url' is reused, whatever its name. Otherwise the remote is called 'owner', which must
+/// not be taken yet. An empty 'url' means the remote 'owner' already exists.
+fn review_remote(
+    remotes: &HashMap<String, Remote>,
+    hosts: &KnownHosts,
+    owner: &str,
+    url: &str,
+) -> Result<String> {
+    if url.is_empty() {
+        return Ok(owner.to_string());
+    }
+    let wanted = Remote {
+        url: url.to_string(),
+    };
+    let mut matching: Vec<&String> = remotes
+        .iter()
+        .filter(|(_, r)| r.same_repository(&wanted, hosts))
+        .map(|(name, _)| name)
+        .collect();
+    // Prefer the conventional name if there are several.
+    matching.sort_by_key(|name| (*name != owner, name.to_string()));
+    if let Some(name) = matching.first() {
+        return Ok(name.to_string());
+    }
+    if let Some(existing) = remotes.get(owner) {
+        return Err(Error::general(format!(
+            "The remote {} points at {}, not at {}. Please rename it.",
+            owner, existing.url, url
+        )));
+    }
+    Ok(owner.to_string())
+}
+
+/// Resolves 'what', a PR number or 'user:branch', in the GitHub repository 'repo_id', which
+/// 'main_remote' called 'main_remote_name' points to.
+async fn review_source_github(
+    what: &str,
+    repo_id: &github::RepoId,
+    main_remote_name: &str,
+    main_remote: &Remote,
+    main_branch: &str,
+) -> Result<ReviewSource> {
+    let (source_branch, target_branch, merge_request) = if let Ok(pr_number) = what.parse::<i32>() {
+        let pr = github::get_pr(&github::PullRequestId {
+            repo: repo_id.clone(),
+            number: pr_number,
+        })
+        .await?;
+        let merge_request = MergeRequest::GitHub(pr.id());
+        (pr.source, pr.target.name, Some(merge_request))
+    } else {
+        let (user, branch) = {
+            let mut it = what.splitn(2, ':');
+            (it.next().unwrap(), it.next().unwrap())
+        };
+
+        let branch = github::Branch {
+            repo: github::RepoId {
+                owner: user.to_string(),
+                name: repo_id.name.clone(),
+            },
+            name: branch.to_string(),
+        };
+        (branch, main_branch.to_string(), None)
+    };
+
+    let (owner, url) = if source_branch.repo == *repo_id {
+        (main_remote_name, main_remote.url.clone())
+    } else {
+        let owner = &source_branch.repo.owner as &str;
+        let url = main_remote.fork_url(&github::host(), owner);
+        (owner, url)
+    };
+    Ok(ReviewSource {
+        remote: owner.to_string(),
+        url,
+        branch: source_branch.name,
+        target_branch,
+        merge_request,
+    })
+}
+
+/// Resolves 'what', a MR number or 'user:branch', in the GitLab 'project', e.g. 'group/project',
+/// which the remote 'main_remote_name' points to.
+async fn review_source_gitlab(
+    what: &str,
+    project: &str,
+    main_remote_name: &str,
+    main_branch: &str,
+) -> Result<ReviewSource> {
+    if let Ok(mr_number) = what.parse::<usize>() {
+        let gitlab = gitlab::GitLab::new()?;
+        let mr = gitlab.get_mr(project, mr_number).await?;
+        let (remote, url) = if mr.source_project_id == mr.target_project_id {
+            (main_remote_name.to_string(), String::new())
+        } else {
+            let source_project = gitlab.get_project(mr.source_project_id).await?;
+            let namespace = source_project
+                .path_with_namespace
+                .rsplit_once('/')
+                .map(|(n, _)| n.to_string())
+                .unwrap_or_default();
+            (namespace, source_project.ssh_url_to_repo)
+        };
+        return Ok(ReviewSource {
+            remote,
+            url,
+            merge_request: Some(MergeRequest::GitLab(mr.id())),
+            branch: mr.source_branch,
+            target_branch: mr.target_branch,
+        });
+    }
+
+    let (user, branch) = what.split_once(':').ok_or_else(|| {
+        Error::general(format!(
+            "review requires a merge request number or user:branch, not {}.",
+            what
+        ))
+    })?;
+    let name = project.rsplit('/').next().unwrap();
+    Ok(ReviewSource {
+        remote: user.to_string(),
+        url: format!("git@{}:{}/{}.git", gitlab::host(), user, name),
+        branch: branch.to_string(),
+        target_branch: main_branch.to_string(),
+        merge_request: None,
+    })
+}
+
+pub async fn handle_review(
+    args: &[&str],
+    repo: &git2::Repository,
+    dbase: &mut diffbase::Diffbase,
+) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optflag(
+        "",
+        "list-files",
+        "Only fetch and list the files changed by the pull request.",
+    );
+    opts.optflag(
+        "",
+        "name-only",
+        "With --list-files, print only the paths instead of grouping them by status.",
+    );
+    opts.optflag(
+        "",
+        "list-mine",
+        "List your own open PRs that have changes requested or failing checks.",
+    );
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(err) => {
+            let brief = format!(
+                "{}\nUsage: g review [options] [<pr number>|<user>:<branch>|push]",
+                err
+            );
+            return Err(Error::general(opts.usage(&brief)));
+        }
+    };
+    let list_files = matches.opt_present("list-files");
+
+    let remotes = get_remotes()?;
+
+    let main_branch = get_main_branch()?;
+    let main_remote_name = get_origin(&main_branch)
+        .map(|o| o.remote)
+        .unwrap_or_else(|| "origin".to_string());
+    let main_remote = remotes
+        .get(&main_remote_name)
+        .ok_or_else(|| Error::general(format!("There is no remote {}.", main_remote_name)))?;
+    match main_remote.repository() {
+        RepositoryType::GitHub(_) | RepositoryType::GitLab(_) => (),
+        _ => {
+            return Err(Error::general(
+                "Cannot handle 'review' for anything but GitHub and GitLab repos currently."
+                    .to_string(),
+            ))
+        }
+    };
+
+    if matches.opt_present("list-mine") {
+        return match main_remote.repository() {
+            RepositoryType::GitHub(s) => print_blocked_prs(&s.repository()).await,
+            _ => Err(Error::general(
+                "--list-mine is only supported for GitHub currently.".to_string(),
+            )),
+        };
+    }
+
+    if matches.free.is_empty() {
+        return match main_remote.repository() {
+            RepositoryType::GitHub(s) => print_assigned_prs(&s.repository()).await,
+            RepositoryType::GitLab(s) => print_assigned_mrs(s.project()).await,
+            _ => unreachable!("Checked above."),
+        };
+    }
+
+    if matches.free.len() != 1 {
+        return Err(Error::general(
+            "review requires a pull request number or a user/branch_name to review.".into(),
+        ));
+    }
+    let what = &matches.free[0];
+
+    if !list_files {
+        expect_working_directory_clean()?;
+    }
+
+    if what == "push" {
+        return handle_review_push(repo);
+    }
+
+    let source = match main_remote.repository() {
+        RepositoryType::GitHub(s) => {
+            review_source_github(
+                what,
+                &s.repository(),
+                &main_remote_name,
+                main_remote,
+                &main_branch,
+            )
+            .await?
+        }
+        RepositoryType::GitLab(s) => {
+            review_source_gitlab(what, s.project(), &main_remote_name, &main_branch).await?
+        }
+        _ => unreachable!("Checked above."),
+    };
+    let owner = &source.remote as &str;
+    let target_branch = source.target_branch;
+    let merge_request = source.merge_request;
+
+    let remote = review_remote(&remotes, &KnownHosts::from_config(), owner, &source.url)?;
+    if !remotes.contains_key(&remote) {
+        run_command(&["git", "remote", "add", &remote, &source.url])?;
+    }
+    run_command(&["git", "fetch", &remote])?;
+    let branch_to_fork = format!("remotes/{}/{}", remote, source.branch);
+
+    if list_files {
+        let target = format!("remotes/{}/{}", main_remote_name, target_branch);
+        let (added, deleted, modified) = get_changed_files(repo, &target, &branch_to_fork)?;
+        print_changed_files(
+            &added,
+            &deleted,
+            &modified,
+            matches.opt_present("name-only"),
+        );
+        return Ok(());
+    }
+
+    let local_branch = format!("|{}/{}", owner, source.branch);
+
+    if get_all_local_branch_names(repo)?.contains(&local_branch) {
+        run_command(&["git", "branch", "-D", &local_branch])?;
+    }
+
+    run_command(&["git", "branch", "--track", &local_branch, &branch_to_fork])?;
+    if let Some(merge_request) = merge_request {
+        dbase.set_merge_request(&local_branch, merge_request);
+    }
+    checkout(repo, &local_branch)?;
+    Ok(())
+}
+
+pub fn checkout(repo: &git2::Repository, branch: &str) -> Result<()> {
+    run_command(&["git", "checkout", branch])?;
+    update_submodules(repo)
+}
+
+/// Returns the ref on 'remote' that points to the head of PR 'number'. GitHub and GitLab keep the
+/// heads of PRs in the base repository, even if the fork is deleted.
+fn pr_head_ref(remote: &Remote, number: u64) -> Result<String> {
+    match remote.repository() {
+        RepositoryType::GitLab(_) => Ok(format!("merge-requests/{}/head", number)),
+        RepositoryType::Bitbucket(_) => Err(Error::general(
+            "Bitbucket does not publish the heads of pull requests. Use 'g review' instead."
+                .to_string(),
+        )),
+        RepositoryType::GitHub(_) | RepositoryType::Unknown => Ok(format!("pull/{}/head", number)),
+    }
+}
+
+/// Fetches the head of PR 'number' into the branch 'pr-<number>' and checks it out. Unlike
+/// 'g review', this needs no remote for the fork and does not touch the diffbase.
+pub fn checkout_pr(repo: &git2::Repository, number: u64) -> Result<()> {
+    let main_branch = get_main_branch()?;
+    let remote_name = get_origin(&main_branch)
+        .map(|o| o.remote)
+        .unwrap_or_else(|| "origin".to_string());
+    let remotes = get_remotes()?;
+    let remote = remotes
+        .get(&remote_name)
+        .ok_or_else(|| Error::general(format!("There is no remote {}.", remote_name)))?;
+    let branch = format!("pr-{}", number);
+    let head_ref = pr_head_ref(remote, number)?;
+    if get_current_branch(repo) == branch {
+        // git refuses to fetch into the branch that is checked out.
+        run_command(&["git", "fetch", &remote_name, &head_ref])?;
+        run_command(&["git", "reset", "--keep", "FETCH_HEAD"])?;
+        return update_submodules(repo);
+    }
+    // Forced, since the PR might have been rebased or amended since the last checkout.
+    let refspec = format!("+{}:{}", head_ref, branch);
+    run_command(&["git", "fetch", &remote_name, &refspec])?;
+    checkout(repo, &branch)
+}
+
+/// Brings the submodules in line with HEAD, if there are any.
+pub fn update_submodules(repo: &git2::Repository) -> Result<()> {
+    if !repo.submodules()?.is_empty() {
+        run_command(&["git", "submodule", "update", "--init", "--recursive"])?;
+    }
+    Ok(())
+}
+
+pub fn handle_clone(args: &[&str]) -> Result<()> {
+    let github_repo_regex =
+        regex::Regex::new(r"^[a-zA-Z\d][a-zA-Z\d-]*/[a-zA-Z\d][a-zA-Z\d-]").unwrap();
+
+    let new_args: Vec<_> = args
+        .iter()
+        .map(|a| {
             if github_repo_regex.is_match(a) {
-                format!("git@github.com:{}.git", a)
+                format!("git@{}:{}.git", github::host(), a)
             } else {
                 a.to_string()
             }
         })
         .collect();
 
-    let args_ref: Vec<_> = new_args.iter().map(|s| s as &str).collect();
-    dispatch_to("git", &args_ref)?;
+    let args_ref: Vec<_> = new_args.iter().map(|s| s as &str).collect();
+    dispatch_to("git", &args_ref)?;
+
+    // Fresh clones often have no origin/HEAD, which get_main_branch relies on.
+    let (directory, remote) = match clone_target(&args_ref[1..]) {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    let set_head = ["git", "-C", &directory, "remote", "set-head", &remote, "-a"];
+    if let Err(err) = communicate_checked(&set_head) {
+        println!("Could not set {}/HEAD: {}", remote, err);
+        return Ok(());
+    }
+    let head = format!("refs/remotes/{}/HEAD", remote);
+    let out = communicate_checked(&["git", "-C", &directory, "symbolic-ref", "--short", &head])?;
+    let main_branch = String::from_utf8(out.stdout)?;
+    let main_branch = main_branch.trim();
+    println!(
+        "The main branch is {}.",
+        main_branch
+            .strip_prefix(&format!("{}/", remote))
+            .unwrap_or(main_branch)
+    );
+    Ok(())
+}
+
+/// Options of 'git clone' that take the next argument as their value.
+const CLONE_OPTIONS_WITH_VALUE: &[&str] = &[
+    "-o",
+    "--origin",
+    "-b",
+    "--branch",
+    "-u",
+    "--upload-pack",
+    "--reference",
+    "--reference-if-able",
+    "--separate-git-dir",
+    "--depth",
+    "--shallow-since",
+    "--shallow-exclude",
+    "-c",
+    "--config",
+    "--template",
+    "-j",
+    "--jobs",
+    "--filter",
+    "--server-option",
+    "--bundle-uri",
+];
+
+/// Returns the directory that 'git clone' with 'args' (without 'clone') clones into and the name
+/// of its remote. Returns None for bare clones, which have no work tree to set up.
+fn clone_target(args: &[&str]) -> Option<(String, String)> {
+    let mut remote = "origin".to_string();
+    let mut positional = Vec::new();
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match *arg {
+            "--bare" | "--mirror" => return None,
+            "--" => positional.extend(it.by_ref()),
+            "-o" | "--origin" => remote = it.next()?.to_string(),
+            a if CLONE_OPTIONS_WITH_VALUE.contains(&a) => {
+                it.next();
+            }
+            a if a.starts_with("--origin=") => remote = a["--origin=".len()..].to_string(),
+            a if a.starts_with('-') => (),
+            a => positional.push(a),
+        }
+    }
+    let directory = match positional.as_slice() {
+        [_, directory] => directory.to_string(),
+        [url] => {
+            // Like git: the last path component without '.git'.
+            let url = url.trim_end_matches('/');
+            let url = url.strip_suffix(".git").unwrap_or(url);
+            url.rsplit(['/', ':']).next()?.to_string()
+        }
+        _ => return None,
+    };
+    if directory.is_empty() {
+        return None;
+    }
+    Some((directory, remote))
+}
+
+/// The fields of a PR that can be used in 'g prs --format'.
+const PRS_FIELDS: &[&str] = &[
+    "host", "repo", "number", "title", "state", "url", "author", "created",
+];
+
+const DEFAULT_PRS_FORMAT: &str = "  - [#{number} • {title}]({url})";
+
+#[derive(Debug, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Field(String),
+}
+
+/// Parses a template like '{repo}#{number}' into its parts. Returns an error for unknown or
+/// unterminated placeholders.
+fn parse_template(template: &str) -> Result<Vec<TemplatePart>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(TemplatePart::Literal(rest[..start].to_string()));
+        }
+        let end = rest[start..].find('}').ok_or_else(|| {
+            Error::general(format!("Unterminated placeholder in '{}'.", template))
+        })?;
+        let field = &rest[start + 1..start + end];
+        if !PRS_FIELDS.contains(&field) {
+            return Err(Error::general(format!(
+                "Unknown placeholder {{{}}}. Valid are: {}.",
+                field,
+                PRS_FIELDS.join(", ")
+            )));
+        }
+        parts.push(TemplatePart::Field(field.to_string()));
+        rest = &rest[start + end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Literal(rest.to_string()));
+    }
+    Ok(parts)
+}
+
+/// A GitHub PR or GitLab MR as reported by 'g prs'.
+#[derive(Serialize)]
+struct PrsEntry {
+    // Either "github" or "gitlab".
+    host: &'static str,
+    repo: String,
+    number: usize,
+    title: String,
+    // One of "open", "closed" or "merged".
+    state: &'static str,
+    url: String,
+    author: String,
+    // YYYY-MM-DD
+    created: String,
+}
+
+impl PrsEntry {
+    fn from_github(pr: &github::PullRequest) -> Self {
+        PrsEntry {
+            host: "github",
+            repo: format!("{}/{}", pr.target.repo.owner, pr.target.repo.name),
+            number: pr.number as usize,
+            title: pr.title.clone(),
+            state: match pr.state {
+                github::PullRequestState::Open => "open",
+                github::PullRequestState::Closed => "closed",
+                github::PullRequestState::Merged => "merged",
+            },
+            url: pr.id().url(),
+            author: pr.author_login.clone(),
+            created: pr.created_at.chars().take(10).collect(),
+        }
+    }
+
+    fn from_gitlab(mr: &gitlab::MergeRequest) -> Self {
+        PrsEntry {
+            host: "gitlab",
+            repo: mr.id().project(),
+            number: mr.number,
+            title: mr.title.clone(),
+            state: match mr.state {
+                gitlab::PullRequestState::Open => "open",
+                gitlab::PullRequestState::Closed => "closed",
+                gitlab::PullRequestState::Merged => "merged",
+            },
+            url: mr.web_url.clone(),
+            author: mr.author.username.clone(),
+            created: mr.created_at.chars().take(10).collect(),
+        }
+    }
+
+    fn field(&self, name: &str) -> String {
+        match name {
+            "host" => self.host.to_string(),
+            "repo" => self.repo.clone(),
+            "number" => self.number.to_string(),
+            "title" => self.title.clone(),
+            "state" => self.state.to_string(),
+            "url" => self.url.clone(),
+            "author" => self.author.clone(),
+            "created" => self.created.clone(),
+            _ => unreachable!("Unknown fields are rejected by parse_template."),
+        }
+    }
+
+    /// The style 'field' is printed in: numbers stand out and titles show if the PR was merged.
+    fn field_style(&self, name: &str) -> Option<term::Attr> {
+        match (name, self.state) {
+            ("number", _) => Some(term::Attr::ForegroundColor(term::color::CYAN)),
+            ("title", "merged") => Some(term::Attr::ForegroundColor(term::color::GREEN)),
+            ("title", "closed") => Some(term::Attr::ForegroundColor(term::color::RED)),
+            _ => None,
+        }
+    }
+
+    /// Prints the entry as a line following 'template'. The fields are styled if there is a
+    /// 'terminal'.
+    fn print(
+        &self,
+        terminal: &mut Option<Box<term::StdoutTerminal>>,
+        template: &[TemplatePart],
+    ) -> Result<()> {
+        for part in template {
+            match part {
+                TemplatePart::Literal(s) => print_styled(terminal, s, None)?,
+                TemplatePart::Field(f) => {
+                    print_styled(terminal, &self.field(f), self.field_style(f))?
+                }
+            }
+        }
+        print_styled(terminal, "\n", None)
+    }
+}
 
+/// Prints 'text' to 'terminal' in 'style', or plainly to stdout if there is no terminal.
+fn print_styled(
+    terminal: &mut Option<Box<term::StdoutTerminal>>,
+    text: &str,
+    style: Option<term::Attr>,
+) -> Result<()> {
+    match (terminal.as_mut(), style) {
+        (Some(t), Some(style)) => {
+            t.attr(style)?;
+            write!(t, "{}", text)?;
+            t.reset()?;
+        }
+        (Some(t), None) => write!(t, "{}", text)?,
+        (None, _) => print!("{}", text),
+    }
     Ok(())
 }
 
@@ -574,13 +2252,56 @@ pub async fn handle_prs(args: &[&str]) -> Result<()> {
         "YYYY-MM-DD",
     );
 
+    opts.optopt(
+        "f",
+        "format",
+        &format!(
+            "Print every PR using this template, or 'json' to print all PRs as a JSON list. \
+             Placeholders: {}. [{}]",
+            PRS_FIELDS
+                .iter()
+                .map(|f| format!("{{{}}}", f))
+                .collect::<Vec<_>>()
+                .join(", "),
+            DEFAULT_PRS_FORMAT
+        ),
+        "TEMPLATE",
+    );
+    opts.optopt(
+        "a",
+        "author",
+        "List the PRs of this user instead of your own. The name must be the same on GitHub \
+         and GitLab.",
+        "USER",
+    );
+    opts.optflag("", "open-all", "Open every listed PR in the web browser.");
+    opts.optflag(
+        "",
+        "open-open-only",
+        "Open every listed PR that is still open in the web browser.",
+    );
+    opts.optflag(
+        "",
+        "no-color",
+        "Do not color the output. This is the default if stdout is no terminal.",
+    );
+
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(err) => {
-            let brief = format!("{}\nUsage: g up [options]", err);
+            let brief = format!("{}\nUsage: g prs [options]", err);
             return Err(Error::general(opts.usage(&brief)));
         }
     };
+    let format = matches
+        .opt_str("format")
+        .unwrap_or_else(|| DEFAULT_PRS_FORMAT.to_string());
+    let json = format == "json";
+    let template = if json {
+        Vec::new()
+    } else {
+        parse_template(&format)?
+    };
 
     let today = Local::now();
     let start = match matches.opt_str("start_date") {
@@ -608,77 +2329,932 @@ pub async fn handle_prs(args: &[&str]) -> Result<()> {
             .unwrap(),
     };
 
-    println!(
-        "Finding GitHub PRs and GitLab MRs from {} to {}.",
+    let author = matches.opt_str("author");
+
+    // Keep stdout clean for whoever is parsing the JSON.
+    eprintln!(
+        "Finding GitHub PRs and GitLab MRs{} from {} to {}.",
+        author
+            .as_ref()
+            .map(|a| format!(" by {}", a))
+            .unwrap_or_default(),
         start.format("%Y-%m-%d"),
         end.format("%Y-%m-%d")
     );
 
-    let (mrs, prs) = try_join!(
-        gitlab::find_my_mrs(start, end),
-        github::find_my_prs(start, end)
-    )?;
-
-    let (mut open_github, mut closed_github) = prs
-        .into_iter()
-        .partition::<Vec<_>, _>(|pr| pr.state == github::PullRequestState::Open);
-    open_github.sort_by_key(|p| (p.target.repo.name.clone(), p.number));
-    closed_github.sort_by_key(|p| (p.target.repo.name.clone(), p.number));
-    let (mut open_gitlab, mut closed_gitlab) = mrs
-        .into_iter()
-        .partition::<Vec<_>, _>(|pr| pr.state == gitlab::PullRequestState::Open);
-    open_gitlab.sort_by_key(|p| p.web_url.clone());
-    closed_gitlab.sort_by_key(|p| p.web_url.clone());
-
-    println!("Closed:");
-    for p in closed_github {
-        println!("  - [#{} • {}]({})", p.number, p.title, p.id().url());
-    }
-    for p in closed_gitlab {
-        println!("  - [#{} • {}]({})", p.number, p.title, p.web_url);
-    }
-
-    println!("\nStill open:");
-    for p in open_github {
-        println!("  - [#{} • {}]({})", p.number, p.title, p.id().url());
+    let (mut mrs, prs) = try_join!(
+        gitlab::find_my_mrs(start, end, author.as_deref()),
+        github::find_my_prs(start, end, author.as_deref())
+    )?;
+    mrs.sort_by_key(|p| p.web_url.clone());
+
+    let mut entries: Vec<PrsEntry> = prs.iter().map(PrsEntry::from_github).collect();
+    entries.extend(mrs.iter().map(PrsEntry::from_gitlab));
+    let (open, closed) = entries
+        .into_iter()
+        .partition::<Vec<_>, _>(|e| e.state == "open");
+
+    if json {
+        let all: Vec<&PrsEntry> = closed.iter().chain(open.iter()).collect();
+        println!("{}", serde_json::to_string_pretty(&all)?);
+    } else {
+        let mut terminal = if matches.opt_present("no-color") || !std::io::stdout().is_terminal() {
+            None
+        } else {
+            term::stdout()
+        };
+        let bold = Some(term::Attr::Bold);
+        print_styled(&mut terminal, "Closed:\n", bold)?;
+        for e in &closed {
+            e.print(&mut terminal, &template)?;
+        }
+
+        print_styled(&mut terminal, "\nStill open:\n", bold)?;
+        for e in &open {
+            e.print(&mut terminal, &template)?;
+        }
+    }
+
+    let to_open: Vec<&PrsEntry> = if matches.opt_present("open-open-only") {
+        open.iter().collect()
+    } else if matches.opt_present("open-all") {
+        closed.iter().chain(open.iter()).collect()
+    } else {
+        Vec::new()
+    };
+    if to_open.len() > MAX_TABS_WITHOUT_ASKING
+        && !prompt::confirm(&format!("Open {} tabs?", to_open.len()), false)?
+    {
+        return Ok(());
+    }
+    for e in to_open {
+        let _ = webbrowser::open(&e.url);
+    }
+
+    Ok(())
+}
+
+/// 'g prs --open-all' asks before opening more tabs than this.
+const MAX_TABS_WITHOUT_ASKING: usize = 10;
+
+/// Returns the name of the branch on 'remote' that the local 'branch' was pushed to, if any.
+fn remote_branch_of(repo: &git2::Repository, branch: &str, remote: &str) -> Option<String> {
+    match get_origin(branch) {
+        Some(o) if o.remote == remote => Some(o.branch),
+        _ => repo
+            .find_reference(&format!("refs/remotes/{}/{}", remote, branch))
+            .ok()
+            .map(|_| branch.to_string()),
+    }
+}
+
+/// Returns the names of all branches on 'remote'.
+fn get_remote_branch_names(remote: &str) -> Result<Vec<String>> {
+    let out = communicate(&["git", "ls-remote", "--heads", remote])?;
+    Ok(String::from_utf8(out.stdout)
+        .unwrap()
+        .lines()
+        .filter_map(|l| l.split_whitespace().nth(1))
+        .map(|r| r.trim_start_matches("refs/heads/").to_string())
+        .collect())
+}
+
+/// Returns an error listing the valid choices if 'branch' does not exist on 'remote'. Pull
+/// requests can only target branches, not tags or arbitrary revisions.
+fn expect_remote_branch(remote: &str, branch: &str) -> Result<()> {
+    let branches = get_remote_branch_names(remote)?;
+    if branches.iter().any(|b| b == branch) {
+        return Ok(());
+    }
+    Err(Error::general(format!(
+        "'{}' is not a branch on {}. Pull requests can only target branches. Valid branches \
+         are:\n\n  {}\n",
+        branch,
+        remote,
+        branches.join("\n  ")
+    )))
+}
+
+/// Pushes HEAD to 'branch' on 'remote'. Refuses to overwrite a remote branch that points to a
+/// different commit unless 'force' is set or it points to 'rewritten_from', the commit HEAD was
+/// before we rewrote it.
+fn push_to_remote_branch(
+    repo: &git2::Repository,
+    remote: &str,
+    branch: &str,
+    force: bool,
+    rewritten_from: Option<git2::Oid>,
+) -> Result<()> {
+    let out = communicate(&[
+        "git",
+        "ls-remote",
+        remote,
+        &format!("refs/heads/{}", branch),
+    ])?;
+    let remote_sha = String::from_utf8(out.stdout)
+        .unwrap()
+        .split_whitespace()
+        .next()
+        .map(|s| s.to_string());
+    let head_sha = repo.revparse_single("HEAD")?.id().to_string();
+
+    let mut push_args = vec!["git", "push"];
+    match remote_sha {
+        Some(ref sha) if *sha != head_sha => {
+            if !force && rewritten_from.map(|r| r.to_string()).as_ref() != Some(sha) {
+                return Err(Error::general(format!(
+                    "{}/{} already exists and points to {}, not HEAD. Use --force to overwrite it.",
+                    remote, branch, sha
+                )));
+            }
+            push_args.push("--force");
+        }
+        _ => (),
+    }
+    let refspec = format!("HEAD:refs/heads/{}", branch);
+    push_args.extend([remote, &refspec]);
+    run_command(&push_args)
+}
+
+/// Prints a short summary of the protection rules of 'branch'. This is purely informational, so
+/// it prints nothing if the rules cannot be fetched, e.g. because the token lacks permissions.
+async fn print_branch_protection(remote: &Remote, branch: &str) {
+    let summary = match remote.repository() {
+        RepositoryType::GitHub(s) => {
+            match github::get_branch_protection(&s.repository(), branch).await {
+                Ok(protection) => {
+                    let mut rules = Vec::new();
+                    if let Some(approvals) = protection.required_approvals {
+                        rules.push(format!("{} approvals", approvals));
+                    }
+                    if !protection.status_checks.is_empty() {
+                        rules.push(format!(
+                            "status checks: {}",
+                            protection.status_checks.join(", ")
+                        ));
+                    }
+                    if rules.is_empty() {
+                        format!(
+                            "base `{}` is protected, but has no review or status gates.",
+                            branch
+                        )
+                    } else {
+                        format!("base `{}` requires {}.", branch, rules.join(", "))
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+        RepositoryType::GitLab(s) => {
+            let gitlab = match gitlab::GitLab::new() {
+                Ok(g) => g,
+                Err(_) => return,
+            };
+            match gitlab.get_protected_branch(s.project(), branch).await {
+                Ok(protection) => {
+                    let levels = |levels: &[gitlab::AccessLevel]| {
+                        levels
+                            .iter()
+                            .map(|l| l.access_level_description.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    };
+                    format!(
+                        "base `{}` is protected, merges allowed for: {}, pushes allowed for: {}.",
+                        protection.name,
+                        levels(&protection.merge_access_levels),
+                        levels(&protection.push_access_levels)
+                    )
+                }
+                Err(_) => return,
+            }
+        }
+        RepositoryType::Bitbucket(_) | RepositoryType::Unknown => return,
+    };
+    println!("{}", summary);
+}
+
+/// Opens the PR of the current branch in the web browser. Without one, the page that compares the
+/// branch against its diffbase or main is opened instead.
+pub fn handle_open(repo: &git2::Repository, dbase: &diffbase::Diffbase) -> Result<()> {
+    let current_branch = get_current_branch(repo);
+    let url = match dbase.get_merge_request(&current_branch) {
+        Some(merge_request) => merge_request.url(),
+        None => {
+            let remotes = get_remotes()?;
+            let main_branch = get_main_branch()?;
+            let base_remote_name = get_origin(&main_branch)
+                .map(|o| o.remote)
+                .unwrap_or_else(|| "origin".to_string());
+            let (head_remote_name, head_branch) = match get_origin(&current_branch) {
+                Some(o) => (o.remote, o.branch),
+                None => {
+                    return Err(Error::general(format!(
+                        "{} has no pull request and no upstream to compare.",
+                        current_branch
+                    )))
+                }
+            };
+            let base_branch = dbase
+                .get_parent(&current_branch)
+                .and_then(|p| remote_branch_of(repo, p, &base_remote_name))
+                .unwrap_or(main_branch);
+            let remote = |name: &str| {
+                remotes
+                    .get(name)
+                    .ok_or_else(|| Error::general(format!("There is no remote {}.", name)))
+            };
+            let (base_remote, head_remote) =
+                (remote(&base_remote_name)?, remote(&head_remote_name)?);
+            // Fails if either remote is not on GitHub, GitLab or Bitbucket.
+            compare_url(base_remote, head_remote, &base_branch, &head_branch)?
+        }
+    };
+    println!("Opening {}.", url);
+    let _ = webbrowser::open(&url);
+    Ok(())
+}
+
+/// Returns the URL of the web page that compares 'head_branch' on 'head_remote' against
+/// 'base_branch' on 'base_remote' and offers to open a PR for it.
+fn compare_url(
+    base_remote: &Remote,
+    head_remote: &Remote,
+    base_branch: &str,
+    head_branch: &str,
+) -> Result<String> {
+    let url = match (base_remote.repository(), head_remote.repository()) {
+        (RepositoryType::GitHub(base), RepositoryType::GitHub(head)) => {
+            let repo_id = base.repository();
+            let head = if head_remote == base_remote {
+                head_branch.to_string()
+            } else {
+                format!("{}:{}", head.owner(), head_branch)
+            };
+            format!(
+                "https://{}/{}/{}/compare/{}...{}",
+                github::host(),
+                repo_id.owner,
+                repo_id.name,
+                base_branch,
+                head
+            )
+        }
+        (RepositoryType::GitLab(base), _) if head_remote == base_remote => format!(
+            "https://{}/{}/-/compare/{}...{}",
+            gitlab::host(),
+            base.project(),
+            base_branch,
+            head_branch
+        ),
+        // GitLab can only compare across forks by project id, but its new merge request page
+        // in the fork targets the upstream project by default.
+        (RepositoryType::GitLab(_), RepositoryType::GitLab(head)) => format!(
+            "https://{}/{}/-/merge_requests/new?merge_request[source_branch]={}&merge_request[target_branch]={}",
+            gitlab::host(),
+            head.project(),
+            head_branch,
+            base_branch
+        ),
+        (RepositoryType::Bitbucket(base), RepositoryType::Bitbucket(head)) => {
+            let (base, head) = (base.repository(), head.repository());
+            format!(
+                "https://bitbucket.org/{}/{}/pull-requests/new?source={}&dest={}/{}::{}",
+                head.workspace, head.slug, head_branch, base.workspace, base.slug, base_branch
+            )
+        }
+        _ => {
+            return Err(Error::general(format!(
+                "Can not compare {} against {}, they need to be on the same GitHub, GitLab or Bitbucket.",
+                head_remote.url, base_remote.url
+            )))
+        }
+    };
+    Ok(url)
+}
+
+/// Warns if 'branch' is stacked deeper than giti.pr.maxStackDepth, since deep stacks are hard to
+/// review. Without the setting, any depth is fine.
+fn warn_about_deep_stack(dbase: &diffbase::Diffbase, branch: &str) {
+    let max_depth = match config::get_string("giti.pr.maxStackDepth").and_then(|d| d.parse().ok()) {
+        Some(d) => d,
+        None => return,
+    };
+    let depth = dbase.depth(branch);
+    if depth <= max_depth {
+        return;
+    }
+    let mut chain: Vec<&str> = dbase.get_ancestors(branch);
+    chain.extend(dbase.get_root(branch));
+    chain.reverse();
+    eprintln!(
+        "Warning: {} is stacked {} deep, more than giti.pr.maxStackDepth ({}): {}",
+        branch,
+        depth,
+        max_depth,
+        chain.join(" -> ")
+    );
+}
+
+/// Returns the DCO 'Signed-off-by' trailer for the configured git user.
+fn signoff_trailer(repo: &git2::Repository) -> Result<String> {
+    let config = repo.config()?;
+    let name = config.get_string("user.name");
+    let email = config.get_string("user.email");
+    match (name, email) {
+        (Ok(name), Ok(email)) => Ok(format!("Signed-off-by: {} <{}>", name, email)),
+        _ => Err(Error::general(
+            "user.name and user.email need to be configured for --signoff.".to_string(),
+        )),
+    }
+}
+
+/// Returns the values of the repeatable, comma separated option 'name', or the list in 'config_key'
+/// if the option is not given.
+fn list_opt_or_config(matches: &getopts::Matches, name: &str, config_key: &str) -> Vec<String> {
+    if !matches.opt_present(name) {
+        return config::get_list(config_key);
+    }
+    split_list(&matches.opt_strs(name))
+}
+
+/// Splits repeated options that can also be comma separated, e.g. '-r a,b -r c', into their values.
+fn split_list(values: &[String]) -> Vec<String> {
+    values
+        .iter()
+        .flat_map(|r| r.split(','))
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty())
+        .collect()
+}
+
+/// Marks the draft PR of the current branch as ready for review, then swaps its labels: first
+/// 'remove_labels' are removed, then 'add_labels' are added.
+async fn mark_pr_ready(
+    repo: &git2::Repository,
+    dbase: &diffbase::Diffbase,
+    remove_labels: &[String],
+    add_labels: &[String],
+) -> Result<()> {
+    let current_branch = get_current_branch(repo);
+    match dbase.get_merge_request(&current_branch) {
+        None => Err(Error::general(format!(
+            "{} has no pull request associated with it.",
+            current_branch
+        ))),
+        Some(MergeRequest::GitHub(pr_id)) => {
+            github::mark_ready_for_review(pr_id).await?;
+            if !remove_labels.is_empty() {
+                github::remove_labels(pr_id, remove_labels).await?;
+            }
+            if !add_labels.is_empty() {
+                github::add_labels(pr_id, add_labels).await?;
+            }
+            println!("Marked {} as ready for review.", pr_id.url());
+            Ok(())
+        }
+        Some(MergeRequest::GitLab(mr_id)) => {
+            gitlab::GitLab::new()?
+                .mark_ready(&mr_id.project(), mr_id.number(), remove_labels, add_labels)
+                .await?;
+            println!("Marked {} as ready for review.", mr_id.url);
+            Ok(())
+        }
+        Some(MergeRequest::Bitbucket(_)) => Err(Error::general(
+            "--ready is not supported for Bitbucket.".to_string(),
+        )),
+    }
+}
+
+/// Lets the user edit the title and body of the PR of the current branch in the editor, in the same
+/// format that is used for opening a PR.
+async fn edit_pr(repo: &git2::Repository, dbase: &diffbase::Diffbase) -> Result<()> {
+    let current_branch = get_current_branch(repo);
+    let merge_request = dbase.get_merge_request(&current_branch).ok_or_else(|| {
+        Error::general(format!(
+            "{} has no pull request associated with it.",
+            current_branch
+        ))
+    })?;
+    let (title, body) = match merge_request {
+        MergeRequest::GitHub(pr_id) => {
+            let pr = github::get_pr(pr_id).await?;
+            (pr.title, pr.body)
+        }
+        MergeRequest::GitLab(mr_id) => {
+            let mr = gitlab::GitLab::new()?
+                .get_mr(&mr_id.project(), mr_id.number())
+                .await?;
+            (mr.title, mr.description.unwrap_or_default())
+        }
+        MergeRequest::Bitbucket(_) => {
+            return Err(Error::general(
+                "--edit is not supported for Bitbucket.".to_string(),
+            ))
+        }
+    };
+
+    let mut temp_file = tempfile::Builder::new()
+        .prefix("COMMIT_EDITMSG")
+        .rand_bytes(0)
+        .tempfile()?;
+    write!(temp_file, "{}\n\n{}\n", title, body.replace("\r\n", "\n"))?;
+    let temp_path = temp_file.into_temp_path();
+    run_editor(&temp_path)?;
+    let (title, body) = parse_pr_message(&::std::fs::read_to_string(&temp_path)?)?;
+    let body = body.unwrap_or_default();
+
+    match merge_request {
+        MergeRequest::GitHub(pr_id) => github::update_pr(pr_id, &title, &body).await?,
+        MergeRequest::GitLab(mr_id) => {
+            gitlab::GitLab::new()?
+                .update_mr(&mr_id.project(), mr_id.number(), &title, &body)
+                .await?
+        }
+        MergeRequest::Bitbucket(_) => unreachable!("Rejected above."),
+    }
+    println!("Updated {}.", merge_request);
+    Ok(())
+}
+
+/// How often the editor is opened again for a title that does not match giti.pr.titlePattern.
+const DEFAULT_TITLE_ATTEMPTS: usize = 3;
+
+/// Returns the regex from giti.pr.titlePattern that PR titles must match, if it is set.
+fn pr_title_pattern() -> Result<Option<regex::Regex>> {
+    match config::get_string("giti.pr.titlePattern").filter(|p| !p.is_empty()) {
+        None => Ok(None),
+        Some(pattern) => regex::Regex::new(&pattern).map(Some).map_err(|err| {
+            Error::general(format!(
+                "giti.pr.titlePattern is not a valid regex: {}",
+                err
+            ))
+        }),
+    }
+}
+
+/// Lets the user write the message of a new PR in the editor, starting from the repository's
+/// template. If only one of 'title' and 'body' was given on the command line, the message starts
+/// out with it instead. The message is kept in 'last_message_path' until the PR was opened.
+fn write_pr_message(
+    repo: &git2::Repository,
+    base_remote: &Remote,
+    last_message_path: &Path,
+    title: Option<&str>,
+    body: Option<&str>,
+    yes: bool,
+) -> Result<String> {
+    let mut temp_file = tempfile::Builder::new()
+        .prefix("COMMIT_EDITMSG")
+        .rand_bytes(0)
+        .tempfile()?;
+
+    let workdir = repo.workdir().unwrap();
+    let template = || match base_remote.repository() {
+        RepositoryType::GitLab(_) => gitlab::get_merge_request_template(workdir),
+        _ => github::get_pull_request_template(workdir),
+    };
+    if title.is_some() || body.is_some() {
+        let body = body.map(|b| b.to_string()).or_else(template);
+        let seed = format!(
+            "{}\n\n{}",
+            title.unwrap_or_default(),
+            body.unwrap_or_default()
+        );
+        temp_file.write_all(seed.as_bytes())?;
+    } else {
+        // If the last 'g pr' failed after the message was written, offer it again.
+        let last_message = ::std::fs::read_to_string(last_message_path)
+            .ok()
+            .filter(|m| !m.trim().is_empty());
+        let restore =
+            match &last_message {
+                None => false,
+                Some(_) if yes => true,
+                Some(_) if !std::io::stdin().is_terminal() => return Err(Error::general(
+                    "The message of the last failed 'g pr' is left, but there is no terminal to \
+                     ask whether to restore it. Pass --yes to restore it."
+                        .into(),
+                )),
+                Some(_) => prompt::confirm("Restore the message of the last failed 'g pr'?", true)?,
+            };
+        let restored = match last_message {
+            Some(msg) if restore => {
+                temp_file.write_all(msg.as_bytes())?;
+                true
+            }
+            _ => false,
+        };
+        if !restored {
+            if let Some(msg) = template() {
+                temp_file.write_all(msg.as_bytes())?
+            }
+        }
+    }
+    let temp_path = temp_file.into_temp_path();
+
+    run_editor(&temp_path)?;
+    let content = ::std::fs::read_to_string(&temp_path)?.trim().to_string();
+    ::std::fs::write(last_message_path, &content)?;
+    Ok(content)
+}
+
+/// Builds the message of a PR from the commits between 'base' and HEAD: the title is the summary
+/// of the latest commit, the body its remaining message followed by the messages of all earlier
+/// commits, oldest first.
+fn message_from_commits(
+    repo: &git2::Repository,
+    base: git2::Oid,
+) -> Result<(String, Option<String>)> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+    revwalk.push_head()?;
+    revwalk.hide(base)?;
+    let mut messages = Vec::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        messages.push(commit.message().unwrap_or_default().trim().to_string());
+    }
+    if messages.is_empty() {
+        return Err(Error::general(
+            "There are no commits to fill the PR message from.".into(),
+        ));
+    }
+    let latest = messages.remove(0);
+    let (title, latest_body) = match latest.split_once('\n') {
+        Some((title, body)) => (title.trim().to_string(), body.trim().to_string()),
+        None => (latest, String::new()),
+    };
+    let body: Vec<String> = Some(latest_body)
+        .into_iter()
+        .chain(messages.into_iter().rev())
+        .filter(|m| !m.is_empty())
+        .collect();
+    let body = if body.is_empty() {
+        None
+    } else {
+        Some(body.join("\n\n"))
+    };
+    Ok((title, body))
+}
+
+/// Splits the message of a PR into the title on the first line and the body, which starts on the
+/// third line. Lines in the body keep their indentation, it matters for code blocks and lists.
+fn parse_pr_message(content: &str) -> Result<(String, Option<String>)> {
+    let lines: Vec<&str> = content.trim_start().lines().collect();
+    if lines.is_empty() {
+        return Err(Error::general("No message, no PR.".into()));
+    }
+    let title = lines[0].trim().to_string();
+    let mut body = lines.get(2..).unwrap_or_default();
+    while let Some((first, rest)) = body.split_first() {
+        if !first.trim().is_empty() {
+            break;
+        }
+        body = rest;
+    }
+    while let Some((last, rest)) = body.split_last() {
+        if !last.trim().is_empty() {
+            break;
+        }
+        body = rest;
+    }
+    let body = if body.is_empty() {
+        None
+    } else {
+        Some(body.join("\n"))
+    };
+    Ok((title, body))
+}
+
+/// File in the git directory that records when each team member was last picked by
+/// 'g pr --reviewer-random'. Maps team -> login -> unix timestamp.
+const REVIEWER_ROTATION_FILE: &str = "giti_reviewer_rotation.json";
+
+/// Picks the one of 'candidates' that was least recently picked as reviewer for 'team' in this
+/// repository and records the pick. Candidates that were never picked come first.
+fn pick_team_reviewer(
+    repo: &git2::Repository,
+    team: &str,
+    candidates: &[String],
+) -> Result<Option<String>> {
+    let path = repo.path().join(REVIEWER_ROTATION_FILE);
+    let mut rotation: HashMap<String, HashMap<String, i64>> = ::std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|c| serde_json::from_str(&c).ok())
+        .unwrap_or_default();
+    let last_picked = rotation.entry(team.to_string()).or_default();
+
+    let reviewer = match candidates
+        .iter()
+        .min_by_key(|c| (last_picked.get(*c).copied().unwrap_or(0), c.to_string()))
+    {
+        None => return Ok(None),
+        Some(r) => r.clone(),
+    };
+    last_picked.insert(reviewer.clone(), Local::now().timestamp());
+    ::std::fs::write(&path, serde_json::to_string_pretty(&rotation)?)?;
+    Ok(Some(reviewer))
+}
+
+/// File in the git directory that keeps the message of the last 'g pr' until the pull request
+/// was successfully created.
+const LAST_PR_MESSAGE_FILE: &str = "giti_last_pr_message";
+
+pub async fn handle_pr(
+    args: &[&str],
+    repo: &git2::Repository,
+    dbase: &mut diffbase::Diffbase,
+) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optflag(
+        "",
+        "base-ref-from-upstream",
+        "Target the branch the current branch tracks (branch.<name>.merge) instead of main.",
+    );
+    opts.optopt(
+        "",
+        "base",
+        "The branch on the base remote to open the PR against. [diffbase or main]",
+        "BRANCH",
+    );
+    opts.optopt(
+        "",
+        "head-branch",
+        "Push HEAD to this remote branch and open the PR from it.",
+        "NAME",
+    );
+    opts.optflag(
+        "f",
+        "force",
+        "Overwrite the --head-branch on the remote even if it points elsewhere.",
+    );
+    opts.optflag(
+        "",
+        "target-branch-protection-check",
+        "Summarize the protection rules of the base branch before opening the PR.",
+    );
+    opts.optflag(
+        "s",
+        "signoff",
+        "Append a Signed-off-by trailer for the configured git user to the PR body.",
+    );
+    opts.optflag(
+        "",
+        "amend-signoff",
+        "Also add the Signed-off-by trailer to all commits of the branch and force push them.",
+    );
+    opts.optflag("", "assign-me", "Assign the new PR to yourself.");
+    opts.optflag(
+        "y",
+        "yes",
+        "Answer yes to all questions, e.g. restore the message of the last failed 'g pr'.",
+    );
+    opts.optflag(
+        "",
+        "draft",
+        "Open the PR as a draft. On GitLab, the title is prefixed with 'Draft:'.",
+    );
+    opts.optmulti(
+        "r",
+        "reviewer",
+        "Request a review from these users (comma separated, repeatable). \
+         Overrides giti.pr.reviewers.",
+        "USER",
+    );
+    opts.optmulti(
+        "l",
+        "label",
+        "Add these labels to the PR (comma separated, repeatable).",
+        "LABEL",
+    );
+    opts.optflag(
+        "",
+        "no-reviewers",
+        "Do not request the reviewers configured in giti.pr.reviewers.",
+    );
+    opts.optopt(
+        "",
+        "reviewer-random",
+        "Also request a review from the member of this team that was picked least recently.",
+        "ORG/TEAM",
+    );
+    opts.optflag(
+        "",
+        "print-url",
+        "Only print the compare URL for the branch and exit. Needs no token.",
+    );
+    opts.optflag(
+        "w",
+        "web",
+        "Open the page to create the PR in the web browser instead. Needs no token.",
+    );
+    opts.optflag(
+        "",
+        "ready",
+        "Mark the draft PR of the current branch as ready for review and exit.",
+    );
+    opts.optflag(
+        "",
+        "edit",
+        "Edit the title and body of the PR of the current branch in the editor and exit.",
+    );
+    opts.optflag(
+        "",
+        "fill",
+        "Take the title and body from the commits of the branch instead of opening the editor.",
+    );
+    opts.optopt(
+        "",
+        "title",
+        "Use this title. Together with a body, the editor is not opened.",
+        "TITLE",
+    );
+    opts.optopt(
+        "",
+        "body",
+        "Use this body. Together with a title, the editor is not opened.",
+        "TEXT",
+    );
+    opts.optopt(
+        "",
+        "body-file",
+        "Read the body from this file, '-' for stdin. Together with a title, the editor is not \
+         opened.",
+        "PATH",
+    );
+    opts.optmulti(
+        "",
+        "labels-remove-on-ready",
+        "Labels to remove with --ready (comma separated, repeatable). \
+         Overrides giti.pr.readyRemoveLabels.",
+        "LABEL",
+    );
+    opts.optmulti(
+        "",
+        "labels-add-on-ready",
+        "Labels to add with --ready (comma separated, repeatable). \
+         Overrides giti.pr.readyAddLabels.",
+        "LABEL",
+    );
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(err) => {
+            let brief = format!("{}\nUsage: g pr [options]", err);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+    };
+
+    if matches.opt_present("ready") {
+        let remove_labels = list_opt_or_config(
+            &matches,
+            "labels-remove-on-ready",
+            "giti.pr.readyRemoveLabels",
+        );
+        let add_labels =
+            list_opt_or_config(&matches, "labels-add-on-ready", "giti.pr.readyAddLabels");
+        return mark_pr_ready(repo, dbase, &remove_labels, &add_labels).await;
     }
-    for p in open_gitlab {
-        println!("  - [#{} • {}]({})", p.number, p.title, p.web_url);
+    if matches.opt_present("edit") {
+        return edit_pr(repo, dbase).await;
     }
 
-    Ok(())
-}
+    let assign_me = matches.opt_present("assign-me");
+    let draft = matches.opt_present("draft");
+    let given_title = matches.opt_str("title");
+    let given_body = match (matches.opt_str("body"), matches.opt_str("body-file")) {
+        (Some(_), Some(_)) => {
+            return Err(Error::general(
+                "Only one of --body and --body-file can be given.".to_string(),
+            ))
+        }
+        (Some(body), None) => Some(body),
+        (None, Some(path)) if path == "-" => {
+            let mut body = String::new();
+            ::std::io::Read::read_to_string(&mut ::std::io::stdin(), &mut body)?;
+            Some(body)
+        }
+        (None, Some(path)) => Some(::std::fs::read_to_string(&path).map_err(|err| {
+            Error::general(format!("Could not read the body from {}: {}", path, err))
+        })?),
+        (None, None) => None,
+    };
+    let labels = split_list(&matches.opt_strs("label"));
+    let mut reviewers: Vec<String> = if matches.opt_present("no-reviewers") {
+        Vec::new()
+    } else {
+        list_opt_or_config(&matches, "reviewer", "giti.pr.reviewers")
+    };
 
-pub async fn handle_pr(
-    _args: &[&str],
-    repo: &git2::Repository,
-    dbase: &mut diffbase::Diffbase,
-) -> Result<()> {
     let local_branches = get_all_local_branches(repo)?;
     let current_branch = get_current_branch(repo);
+    warn_about_deep_stack(dbase, &current_branch);
 
     let remotes = get_remotes()?;
-    let main_branch = get_main_branch();
-    let base_remote = {
-        let origin = match get_origin(&main_branch) {
-            None => get_origin(&current_branch).ok_or(Error::general(
-                "Unable to find origin for merge request.".to_string(),
-            ))?,
-            Some(o) => o,
-        };
-        &remotes[&origin.remote]
+    let main_branch = get_main_branch()?;
+    let base_origin = match get_origin(&main_branch) {
+        None => get_origin(&current_branch).ok_or(Error::general(
+            "Unable to find origin for merge request.".to_string(),
+        ))?,
+        Some(o) => o,
     };
+    let base_remote = &remotes[&base_origin.remote];
 
-    if local_branches[&current_branch].upstream.is_none() {
-        return Err(Error::general(
-            "current branch has no upstream (maybe git push -u?). \
-             Cannot open a pull request."
-                .into(),
-        ));
-    }
+    let head_branch = matches.opt_str("head-branch");
     // Could be "SirVer/foobar" or "origin/foobar"
-    let head_upstream = &local_branches[&current_branch].upstream.clone().unwrap();
-    let head_remote = &remotes[head_upstream.split('/').next().unwrap()];
+    let head_remote_name = match local_branches[&current_branch].upstream {
+        Some(ref upstream) => upstream.split('/').next().unwrap().to_string(),
+        None if head_branch.is_some() => base_origin.remote.clone(),
+        None => {
+            return Err(Error::general(
+                "current branch has no upstream (maybe git push -u?). \
+                 Cannot open a pull request."
+                    .into(),
+            ))
+        }
+    };
+    let head_remote = &remotes[&head_remote_name];
+    // The branch the PR should be merged into. If requested, this is the branch we track
+    // upstream, unless that is just our own branch pushed with 'push -u'.
+    let base_branch = match (matches.opt_str("base"), get_origin(&current_branch)) {
+        (Some(base), _) => {
+            expect_remote_branch(&base_origin.remote, &base)?;
+            base
+        }
+        (None, Some(o))
+            if matches.opt_present("base-ref-from-upstream")
+                && o.branch != current_branch
+                && o.branch != main_branch =>
+        {
+            o.branch
+        }
+        _ => match dbase.get_parent(&current_branch) {
+            None => main_branch.clone(),
+            Some(parent) => match remote_branch_of(repo, parent, &base_origin.remote) {
+                Some(branch) => branch,
+                None => {
+                    println!(
+                        "The diffbase {} is not pushed to {}, opening the PR against {}.",
+                        parent, base_origin.remote, main_branch
+                    );
+                    main_branch.clone()
+                }
+            },
+        },
+    };
+
+    if matches.opt_present("print-url") || matches.opt_present("web") {
+        let head_branch = head_branch.unwrap_or_else(|| current_branch.clone());
+        let url = compare_url(base_remote, head_remote, &base_branch, &head_branch)?;
+        if !matches.opt_present("web") {
+            println!("{}", url);
+            return Ok(());
+        }
+        // Without 'expand', GitHub only shows the diff and not the form to open the PR.
+        let url = match base_remote.repository() {
+            RepositoryType::GitHub(_) => format!("{}?expand=1", url),
+            _ => url,
+        };
+        println!("Opening {}.", url);
+        let _ = webbrowser::open(&url);
+        return Ok(());
+    }
+
+    if let Some(team) = matches.opt_str("reviewer-random") {
+        let (members, me) = match base_remote.repository() {
+            RepositoryType::GitHub(_) => {
+                let (org, slug) = team.split_once('/').ok_or_else(|| {
+                    Error::general(format!("Team must be given as org/team, not {}.", team))
+                })?;
+                // Installation tokens have no login, so there is no one to exclude.
+                (
+                    github::get_team_members(org, slug).await?,
+                    github::get_login().await.unwrap_or_default(),
+                )
+            }
+            RepositoryType::GitLab(_) => {
+                let gitlab = gitlab::GitLab::new()?;
+                (
+                    gitlab.get_group_members(&team).await?,
+                    gitlab.find_user_name().await?,
+                )
+            }
+            RepositoryType::Bitbucket(_) => {
+                return Err(Error::general(
+                    "--reviewer-random is not supported for Bitbucket.".to_string(),
+                ))
+            }
+            RepositoryType::Unknown => unreachable!("PR only implemented for GitLab & GitHub."),
+        };
+        let candidates: Vec<String> = members
+            .into_iter()
+            .filter(|m| *m != me && !reviewers.contains(m))
+            .collect();
+        let reviewer = pick_team_reviewer(repo, &team, &candidates)?
+            .ok_or_else(|| Error::general(format!("{} has no one else to review.", team)))?;
+        println!("Requesting a review from {} of {}.", reviewer, team);
+        reviewers.push(reviewer);
+    }
 
     // expect_working_directory_clean()?;
 
@@ -690,93 +3266,334 @@ pub async fn handle_pr(
         )));
     }
 
-    // Get PR original post message.
-    let mut temp_file = tempfile::Builder::new()
-        .prefix("COMMIT_EDITMSG")
-        .rand_bytes(0)
-        .tempfile()?;
-
-    if let Some(msg) = github::get_pull_request_template(repo.workdir().unwrap()) {
-        temp_file.write_all(msg.as_bytes())?
+    // HEAD before the trailers were added, the --head-branch might still point to it.
+    let mut rewritten_from = None;
+    if matches.opt_present("amend-signoff") {
+        let base = repo.revparse_single(&format!("{}/{}", base_origin.remote, base_branch))?;
+        let head = repo.revparse_single("HEAD")?;
+        let merge_base = repo.merge_base(base.id(), head.id())?;
+        run_command(&["git", "rebase", "--signoff", &merge_base.to_string()])?;
+        if head_branch.is_none() {
+            run_command(&["git", "push", "--force-with-lease"])?;
+        }
+        rewritten_from = Some(head.id());
     }
-    let temp_path = temp_file.into_temp_path();
 
-    run_editor(&temp_path)?;
-    let content = ::std::fs::read_to_string(&temp_path)?.trim().to_string();
-    let lines: Vec<String> = content.lines().map(|l| l.trim().to_string()).collect();
-    if lines.is_empty() {
-        return Err(Error::general("No message, no PR.".into()));
+    // The name of the branch on the remote that the PR is opened from.
+    let head_branch = match head_branch {
+        None => current_branch.clone(),
+        Some(name) => {
+            push_to_remote_branch(
+                repo,
+                &head_remote_name,
+                &name,
+                matches.opt_present("force"),
+                rewritten_from,
+            )?;
+            name
+        }
+    };
+
+    if matches.opt_present("target-branch-protection-check") {
+        print_branch_protection(base_remote, &base_branch).await;
     }
-    let title = lines[0].to_string();
-    let body = if lines.len() > 2 {
-        Some(lines[2..].join("\n"))
+
+    let last_message_path = repo.path().join(LAST_PR_MESSAGE_FILE);
+    let title_pattern = pr_title_pattern()?;
+    let (title, mut body) = if matches.opt_present("fill") {
+        let base = repo
+            .revparse_single(&format!("{}/{}", base_origin.remote, base_branch))
+            .or_else(|_| repo.revparse_single(&base_branch))?;
+        let (title, body) = message_from_commits(repo, base.id())?;
+        (given_title.unwrap_or(title), given_body.or(body))
+    } else if let (Some(title), Some(body)) = (&given_title, &given_body) {
+        if title.trim().is_empty() {
+            return Err(Error::general("No title, no PR.".into()));
+        }
+        let body = Some(body.trim().to_string()).filter(|b| !b.is_empty());
+        (title.trim().to_string(), body)
     } else {
-        None
+        let attempts = config::get_string("giti.pr.titleAttempts")
+            .and_then(|a| a.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_TITLE_ATTEMPTS);
+        let (mut title, mut body) = (given_title, given_body);
+        let mut attempt = 1;
+        loop {
+            let message = write_pr_message(
+                repo,
+                base_remote,
+                &last_message_path,
+                title.as_deref(),
+                body.as_deref(),
+                matches.opt_present("yes"),
+            )?;
+            let (new_title, new_body) = parse_pr_message(&message)?;
+            match &title_pattern {
+                Some(pattern) if !pattern.is_match(&new_title) && attempt < attempts => {
+                    println!("{}\n", message);
+                    println!(
+                        "The title does not match giti.pr.titlePattern '{}'. Please fix it.",
+                        pattern
+                    );
+                    if !matches.opt_present("yes")
+                        && !prompt::confirm("Open the editor again?", true)?
+                    {
+                        return Err(Error::general("No valid title, no PR.".into()));
+                    }
+                    (title, body) = (Some(new_title), new_body);
+                    attempt += 1;
+                }
+                _ => break (new_title, new_body),
+            }
+        }
     };
+    if let Some(pattern) = &title_pattern {
+        if !pattern.is_match(&title) {
+            return Err(Error::general(format!(
+                "The title '{}' does not match giti.pr.titlePattern '{}'.",
+                title, pattern
+            )));
+        }
+    }
+    if let Some(issue) = dbase.get_issue(&current_branch) {
+        let closes = format!("Closes #{}", issue);
+        body = match body {
+            None => Some(closes),
+            Some(b) if b.contains(&closes) => Some(b),
+            Some(b) => Some(format!("{}\n\n{}", b, closes)),
+        };
+    }
+    if matches.opt_present("signoff") {
+        let trailer = signoff_trailer(repo)?;
+        body = Some(match body {
+            None => trailer,
+            Some(b) => format!("{}\n\n{}", b, trailer),
+        });
+    }
 
     let url = match base_remote.repository() {
         RepositoryType::GitHub(s) => {
             let repo_id = s.repository();
             // Base to merge from. If it is in the same fork as base, it must not contain the owners name.
             let head = if head_remote == base_remote {
-                current_branch.clone()
+                head_branch.clone()
             } else {
                 let owner = match head_remote.repository() {
                     RepositoryType::GitHub(s) => s.owner().to_string(),
                     _ => unreachable!("Head cannot not be GitHub since base is."),
                 };
-                format!("{}:{}", owner, current_branch)
+                format!("{}:{}", owner, head_branch)
             };
 
             let pull_options = hubcaps_ex::pulls::PullOptions {
                 title,
                 body,
                 head,
-                base: main_branch,
+                base: base_branch,
             };
 
-            let pr = github::create_pr(&repo_id, pull_options).await?.id();
+            let pr = github::create_pr(&repo_id, pull_options, draft).await?.id();
             dbase.set_merge_request(&current_branch, MergeRequest::GitHub(pr.clone()));
+            if assign_me {
+                let assigned = match github::get_login().await {
+                    Ok(login) => github::add_assignees(&pr, &[login]).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = assigned {
+                    println!("Could not assign {} to you: {}", pr, err);
+                }
+            }
+            if !reviewers.is_empty() {
+                if let Err(err) = github::request_reviewers(&pr, &reviewers).await {
+                    println!("Could not request reviewers for {}: {}", pr, err);
+                }
+            }
+            if !labels.is_empty() {
+                if let Err(err) = github::add_labels(&pr, &labels).await {
+                    println!("Could not add labels to {}: {}", pr, err);
+                }
+            }
             pr.url()
         }
         RepositoryType::GitLab(s) => {
             let gitlab = gitlab::GitLab::new().unwrap();
+            let title = if draft {
+                format!("Draft: {}", title)
+            } else {
+                title
+            };
             let mr = gitlab
                 .create_mr(
                     s.project(),
-                    &current_branch,
-                    &main_branch,
+                    &head_branch,
+                    &base_branch,
                     &title,
                     &body.unwrap_or("".to_string()),
+                    &labels,
                 )
                 .await?;
             dbase.set_merge_request(&current_branch, MergeRequest::GitLab(mr.id()));
+            if assign_me {
+                let assigned = match gitlab.find_user_id().await {
+                    Ok(id) => gitlab.set_assignees(s.project(), mr.number, &[id]).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = assigned {
+                    println!("Could not assign {} to you: {}", mr.web_url, err);
+                }
+            }
+            if !reviewers.is_empty() {
+                if let Err(err) = gitlab
+                    .set_reviewers(s.project(), mr.number, &reviewers)
+                    .await
+                {
+                    println!("Could not request reviewers for {}: {}", mr.web_url, err);
+                }
+            }
             mr.web_url
         }
-        RepositoryType::Unknown => unreachable!("PR only implemented for GitLab & GitHub."),
+        RepositoryType::Bitbucket(s) => {
+            let source = match head_remote.repository() {
+                RepositoryType::Bitbucket(h) => h.repository(),
+                _ => unreachable!("Head cannot not be Bitbucket since base is."),
+            };
+            let bitbucket = bitbucket::Bitbucket::new()?;
+            let pr = bitbucket
+                .create_pr(
+                    &s.repository(),
+                    &source,
+                    &head_branch,
+                    &base_branch,
+                    &bitbucket::PullRequestOptions {
+                        title,
+                        description: body.unwrap_or_default(),
+                        draft,
+                    },
+                )
+                .await?;
+            dbase.set_merge_request(&current_branch, MergeRequest::Bitbucket(pr.id()));
+            if assign_me || !reviewers.is_empty() || !labels.is_empty() {
+                println!("Assignees, reviewers and labels are not supported for Bitbucket yet.");
+            }
+            pr.web_url
+        }
+        RepositoryType::Unknown => {
+            unreachable!("PR only implemented for GitLab, GitHub & Bitbucket.")
+        }
     };
 
+    let _ = ::std::fs::remove_file(&last_message_path);
     println!("Opened {}. Opening in web browser.", url);
     let _ = webbrowser::open(&url);
 
     Ok(())
 }
 
-pub fn handle_start(args: &[&str], repo: &git2::Repository) -> Result<()> {
-    if args.len() != 2 {
-        return Err(Error::general("start requires a branch name.".into()));
-    }
+/// The name of a branch started with 'g start --issue', unless giti.branchTemplate is set.
+const DEFAULT_BRANCH_TEMPLATE: &str = "{issue}-{name}";
+
+/// Returns the name of the branch for 'issue' from 'template', which can use the placeholders
+/// '{issue}' and '{name}'.
+fn issue_branch_name(template: &str, issue: i64, name: &str) -> String {
+    template
+        .replace("{issue}", &issue.to_string())
+        .replace("{name}", name)
+}
+
+/// Creates a new branch off origin's main branch or, if given, off another base and checks it out.
+/// A local base branch becomes the diffbase of the new branch.
+pub fn handle_start(
+    args: &[&str],
+    repo: &git2::Repository,
+    dbase: &mut diffbase::Diffbase,
+) -> Result<()> {
+    let mut opts = getopts::Options::new();
+    opts.optopt(
+        "",
+        "issue",
+        &format!(
+            "Work on this issue: the branch is named after giti.branchTemplate [{}] and 'g pr' \
+             closes the issue.",
+            DEFAULT_BRANCH_TEMPLATE
+        ),
+        "NUMBER",
+    );
+    let usage = "Usage: g start [options] <name> [<base>]";
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) if m.free.len() == 1 || m.free.len() == 2 => m,
+        Ok(_) => {
+            let brief = format!(
+                "start requires a branch name and optionally a base.\n{}",
+                usage
+            );
+            return Err(Error::general(opts.usage(&brief)));
+        }
+        Err(err) => {
+            let brief = format!("{}\n{}", err, usage);
+            return Err(Error::general(opts.usage(&brief)));
+        }
+    };
+    let issue =
+        match matches.opt_str("issue") {
+            None => None,
+            Some(issue) => Some(issue.trim_start_matches('#').parse::<i64>().map_err(|_| {
+                Error::general(format!("--issue must be a number, not {}.", issue))
+            })?),
+        };
+    let branch = match issue {
+        None => matches.free[0].clone(),
+        Some(issue) => {
+            let template = config::get_string("giti.branchTemplate")
+                .unwrap_or_else(|| DEFAULT_BRANCH_TEMPLATE.to_string());
+            issue_branch_name(&template, issue, &matches.free[0])
+        }
+    };
+
     run_command(&["git", "fetch"])?;
-    let origin = format!("origin/{}", get_main_branch());
-    run_command(&["git", "branch", "--no-track", args[1], &origin])?;
-    checkout(repo, args[1])
+    let base = match matches.free.get(1) {
+        None => format!("origin/{}", get_main_branch()?),
+        Some(base) => {
+            if repo.revparse_single(base).is_err() {
+                return Err(Error::general(format!(
+                    "Cannot start {} from {}: no such branch or revision.",
+                    branch, base
+                )));
+            }
+            base.to_string()
+        }
+    };
+    run_command(&["git", "branch", "--no-track", &branch, &base])?;
+    if matches.free.len() == 2 && get_all_local_branch_names(repo)?.contains(&base) {
+        if let Err(err) = dbase.set_diffbase(&branch, &base) {
+            if err.kind != ErrorKind::BranchCantBeDiffbase {
+                return Err(err);
+            }
+        }
+    }
+    if let Some(issue) = issue {
+        dbase.set_issue(&branch, issue);
+    }
+    checkout(repo, &branch)
 }
 
+/// Expands 'command' if it is a git alias. Aliases can refer to other aliases, so the first word is
+/// expanded until it is not an alias anymore. An alias that refers back to itself is left for git
+/// to complain about.
 fn replace_aliases<'a>(command: &'a str, git_aliases: &'a HashMap<String, String>) -> Vec<&'a str> {
-    if let Some(value) = git_aliases.get(command) {
-        return value.split(' ').collect();
+    let mut expanded = vec![command];
+    let mut seen = HashSet::new();
+    while let Some(value) = git_aliases.get(expanded[0]) {
+        if !seen.insert(expanded[0]) {
+            break;
+        }
+        let mut words: Vec<&str> = value.split_whitespace().collect();
+        if words.is_empty() {
+            break;
+        }
+        words.extend_from_slice(&expanded[1..]);
+        expanded = words;
     }
-    vec![command]
+    expanded
 }
 
 pub async fn handle_repository(original_args: &[&str]) -> Result<()> {
@@ -797,6 +3614,9 @@ pub async fn handle_repository(original_args: &[&str]) -> Result<()> {
         // Intercepted commands.
         "clone" => return handle_clone(&expanded_args),
         "prs" => return handle_prs(&expanded_args).await,
+        "config" if config::is_giti_config(&expanded_args) => {
+            return config::handle_config(&expanded_args)
+        }
         _ => (),
     };
 
@@ -805,25 +3625,511 @@ pub async fn handle_repository(original_args: &[&str]) -> Result<()> {
         return dispatch_to("git", &expanded_args);
     }
     let repo = repo.unwrap();
-    let mut dbase = diffbase::Diffbase::new(&repo)?;
+    // Only commands that change the diffbase need to wait for other 'g' processes.
+    let changes_diffbase = matches!(
+        expanded_args[0] as &str,
+        "branch"
+            | "checkout"
+            | "cleanup"
+            | "diffbase"
+            | "init-stack"
+            | "merge"
+            | "pr"
+            | "review"
+            | "start"
+    ) || (expanded_args[0] == "doctor" && expanded_args.contains(&"--fix"));
+    let mut dbase = if changes_diffbase {
+        diffbase::Diffbase::new_locked(&repo)?
+    } else {
+        diffbase::Diffbase::new(&repo)?
+    };
+    // 'g doctor' reports these problems itself.
+    if !changes_diffbase && expanded_args[0] != "doctor" && dbase.has_load_problems() {
+        diffbase::Diffbase::prune(&repo)?;
+    }
 
     let result = match expanded_args[0] as &str {
         // Intercepted commands.
         "branch" => diffbase::handle_branch(&expanded_args, &repo, &mut dbase),
+        "branches" => diffbase::handle_branches(&dbase),
         "checkout" => diffbase::handle_checkout(&expanded_args, &repo, &mut dbase),
-        "cleanup" => handle_cleanup(&repo, &mut dbase).await,
+        "cleanup" => handle_cleanup(&expanded_args, &repo, &mut dbase).await,
+        "diffbase" => diffbase::handle_diffbase(&expanded_args, &repo, &mut dbase),
+        "doctor" => diffbase::handle_doctor(&expanded_args, &mut dbase),
         "down" => diffbase::handle_down(&expanded_args, &repo, &dbase),
         "fix" => handle_fix(&expanded_args, &repo),
+        "fixup" => handle_fixup(&expanded_args, &repo, &dbase),
+        "amend" => handle_amend(&expanded_args, &repo, &dbase),
+        "init-stack" => diffbase::handle_init_stack(&repo, &mut dbase),
+        "uncommitted" => handle_uncommitted(&repo, &dbase),
+        "undo-cleanup" => handle_undo_cleanup(&repo),
         "merge" => diffbase::handle_merge(&expanded_args, &repo, &mut dbase),
+        "open" => handle_open(&repo, &dbase),
         "pullc" => diffbase::handle_pullc(&expanded_args, &repo, &dbase),
+        "rebase" => diffbase::handle_rebase(&expanded_args, &repo, &dbase),
+        "sync" => diffbase::handle_sync(&expanded_args, &repo, &dbase),
         "review" => handle_review(&expanded_args, &repo, &mut dbase).await,
-        "start" => handle_start(&expanded_args, &repo),
+        "squash" => handle_squash(&repo, &dbase),
+        "stacklog" => diffbase::handle_stacklog(&repo, &dbase),
+        "start" => handle_start(&expanded_args, &repo, &mut dbase),
         "up" => diffbase::handle_up(&expanded_args, &repo, &dbase),
+        "tree" => diffbase::handle_tree(&repo, &dbase),
+        "unwip" => diffbase::handle_unwip(&repo, &dbase),
+        "wip" => diffbase::handle_wip(&repo, &dbase),
         "pr" => handle_pr(&expanded_args, &repo, &mut dbase).await,
 
         _ => dispatch_to("git", &expanded_args),
     };
 
-    dbase.write_to_disk()?;
+    if changes_diffbase {
+        dbase.write_to_disk()?;
+    }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        branch_commits, clone_target, formatter_command, has_merged_commits, is_host,
+        issue_branch_name, message_from_commits, parse_pr_message, parse_template, pr_head_ref,
+        replace_aliases, review_remote, Formatter, FormatterConfig, GitHubRepository, KnownHosts,
+        Remote, RepositoryType, Status, TemplatePart,
+    };
+    use crate::bitbucket;
+    use crate::test_util::TestRepo;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    #[test]
+    fn test_message_from_commits() {
+        let test_repo = TestRepo::new();
+        let repo = &test_repo.repo;
+        let commit = |message: &str| test_repo.commit_on_head(message);
+        let base = commit("Base");
+        commit("First\n\nWhy the first.");
+        assert_eq!(
+            message_from_commits(repo, base).unwrap(),
+            ("First".to_string(), Some("Why the first.".to_string()))
+        );
+
+        commit("Second");
+        commit("Third\n\nWhy the third.\n");
+        assert_eq!(
+            message_from_commits(repo, base).unwrap(),
+            (
+                "Third".to_string(),
+                Some("Why the third.\n\nFirst\n\nWhy the first.\n\nSecond".to_string())
+            )
+        );
+
+        let head = repo.head().unwrap().target().unwrap();
+        assert!(message_from_commits(repo, head).is_err());
+    }
+
+    #[test]
+    fn test_has_merged_commits() {
+        let test_repo = TestRepo::new();
+        let repo = &test_repo.repo;
+        let commit =
+            |message: &str, parents: &[git2::Oid]| test_repo.commit(None, message, parents);
+        let base = commit("Base", &[]);
+        let feature = commit("Feature", &[base]);
+        let other = commit("Other", &[base]);
+        let main = commit("Merge branch 'feature'", &[other, feature]);
+        assert!(has_merged_commits(repo, feature, main).unwrap());
+        assert!(!has_merged_commits(repo, main, main).unwrap());
+        assert!(!has_merged_commits(repo, main, feature).unwrap());
+        assert!(!has_merged_commits(repo, other, feature).unwrap());
+        // Branches without commits of their own, started off main at some point.
+        assert!(!has_merged_commits(repo, other, main).unwrap());
+        assert!(!has_merged_commits(repo, base, main).unwrap());
+    }
+
+    #[test]
+    fn test_branch_commits() {
+        let test_repo = TestRepo::new();
+        let repo = &test_repo.repo;
+        let commit =
+            |message: &str, parents: &[git2::Oid]| test_repo.commit(Some("HEAD"), message, parents);
+        let base = commit("Base", &[]);
+        let first = commit("First\n\nWhy the first.", &[base]);
+        let parent = test_repo.commit(None, "Parent", &[base]);
+        let merge = commit("Merge branch 'parent'", &[first, parent]);
+        let second = commit("Second", &[merge]);
+        // Like in 'g squash', the commits since merging in the parent are listed.
+        let merge_base = repo.merge_base(second, parent).unwrap();
+        assert_eq!(
+            branch_commits(repo, merge_base).unwrap(),
+            [(first, "First".to_string()), (second, "Second".to_string())]
+        );
+
+        let head = repo.head().unwrap().target().unwrap();
+        assert!(branch_commits(repo, head).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_replace_aliases() {
+        let aliases: HashMap<String, String> = [
+            ("co", "checkout"),
+            ("cob", "co -b"),
+            ("new", "cob  --track"),
+            ("loop", "loop -x"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(replace_aliases("status", &aliases), vec!["status"]);
+        assert_eq!(replace_aliases("co", &aliases), vec!["checkout"]);
+        assert_eq!(replace_aliases("cob", &aliases), vec!["checkout", "-b"]);
+        assert_eq!(
+            replace_aliases("new", &aliases),
+            vec!["checkout", "-b", "--track"]
+        );
+        assert_eq!(replace_aliases("loop", &aliases), vec!["loop", "-x"]);
+    }
+
+    #[test]
+    fn test_parse_status() {
+        let status = Status::parse(
+            " M src/lib.rs\n\
+             M  staged.rs\n\
+             MM both.rs\n\
+             D  gone.rs\n\
+             \x20D also_gone.rs\n\
+             A  new.rs\n\
+             AM new_and_changed.rs\n\
+             R  old.rs -> renamed.rs\n\
+             RM old2.rs -> renamed2.rs\n\
+             C  orig.rs -> copy.rs\n\
+             UU conflict.rs\n\
+             ?? untracked.rs\n\
+             !! ignored.rs\n",
+        )
+        .unwrap();
+        let set = |paths: &[&str]| paths.iter().map(PathBuf::from).collect();
+        assert_eq!(
+            status.modified,
+            set(&["src/lib.rs", "staged.rs", "both.rs", "conflict.rs"])
+        );
+        assert_eq!(status.deleted, set(&["gone.rs", "also_gone.rs"]));
+        assert_eq!(status.added, set(&["new.rs", "new_and_changed.rs"]));
+        assert_eq!(status.untracked, set(&["untracked.rs"]));
+        assert_eq!(status.renamed.len(), 2);
+        assert_eq!(
+            status.renamed[Path::new("renamed.rs")],
+            PathBuf::from("old.rs")
+        );
+        assert_eq!(
+            status.copied[Path::new("copy.rs")],
+            PathBuf::from("orig.rs")
+        );
+        assert!(!status.is_clean());
+        assert_eq!(status.changed_paths().len(), 11);
+
+        let untracked_only = Status::parse("?? a.rs\n").unwrap();
+        assert!(untracked_only.is_clean());
+        assert!(Status::parse("").unwrap().is_clean());
+        assert!(Status::parse("R  no-arrow.rs\n").is_err());
+    }
+
+    #[test]
+    fn test_github_remote_urls() {
+        for (url, host) in &[
+            ("https://github.com/Org/repo.git", "github.com"),
+            ("https://user@github.com/Org/repo", "github.com"),
+            ("git@github.com:Org/repo.git", "github.com"),
+            (
+                "ssh://git@ssh.github.com:443/Org/repo.git",
+                "ssh.github.com",
+            ),
+            ("git@github-work:Org/repo.git", "github-work"),
+        ] {
+            let remote = Remote {
+                url: url.to_string(),
+            };
+            assert_eq!(remote.host(), *host);
+            let repo_id = GitHubRepository { remote: &remote }.repository();
+            assert_eq!(repo_id.owner, "Org");
+            assert_eq!(repo_id.name, "repo");
+        }
+    }
+
+    #[test]
+    fn test_pr_head_ref() {
+        let head_ref = |url: &str| {
+            let remote = Remote {
+                url: url.to_string(),
+            };
+            pr_head_ref(&remote, 12).ok()
+        };
+        assert_eq!(
+            head_ref("git@github.com:Org/repo.git").as_deref(),
+            Some("pull/12/head")
+        );
+        assert_eq!(
+            head_ref("git@gitlab.com:group/repo.git").as_deref(),
+            Some("merge-requests/12/head")
+        );
+        assert_eq!(head_ref("git@bitbucket.org:workspace/repo.git"), None);
+    }
+
+    #[test]
+    fn test_parse_pr_message() {
+        assert_eq!(
+            parse_pr_message("\n  Title \n\nText:\n\n    code\n  - item\n\n").unwrap(),
+            (
+                "Title".to_string(),
+                Some("Text:\n\n    code\n  - item".to_string())
+            )
+        );
+        assert_eq!(
+            parse_pr_message("Title\n\n \n").unwrap(),
+            ("Title".to_string(), None)
+        );
+        assert!(parse_pr_message(" \n").is_err());
+    }
+
+    #[test]
+    fn test_fork_url() {
+        let fork_url = |url: &str| {
+            Remote {
+                url: url.to_string(),
+            }
+            .fork_url("github.com", "alice")
+        };
+        assert_eq!(
+            fork_url("git@github.com:Org/repo.git"),
+            "git@github.com:alice/repo.git"
+        );
+        assert_eq!(
+            fork_url("https://github.com/Org/repo.git"),
+            "https://github.com/alice/repo.git"
+        );
+        assert_eq!(
+            fork_url("https://github.com/Org/repo"),
+            "https://github.com/alice/repo"
+        );
+    }
+
+    #[test]
+    fn test_review_remote() {
+        let remotes: HashMap<String, Remote> = [
+            ("origin", "git@github.com:Org/repo.git"),
+            ("alice-fork", "https://github.com/Alice/repo"),
+            ("bob", "git@github.com:Bob/other.git"),
+        ]
+        .iter()
+        .map(|(name, url)| {
+            let remote = Remote {
+                url: url.to_string(),
+            };
+            (name.to_string(), remote)
+        })
+        .collect();
+        let hosts = KnownHosts {
+            github: (vec!["github.com".to_string()], Vec::new()),
+            gitlab: (vec!["gitlab.com".to_string()], Vec::new()),
+            bitbucket: (vec!["bitbucket.org".to_string()], Vec::new()),
+        };
+        assert_eq!(
+            review_remote(&remotes, &hosts, "alice", "git@github.com:alice/repo.git").unwrap(),
+            "alice-fork"
+        );
+        assert_eq!(
+            review_remote(&remotes, &hosts, "origin", "git@github.com:Org/repo.git").unwrap(),
+            "origin"
+        );
+        assert_eq!(
+            review_remote(&remotes, &hosts, "carol", "git@github.com:carol/repo.git").unwrap(),
+            "carol"
+        );
+        assert_eq!(
+            review_remote(&remotes, &hosts, "origin", "").unwrap(),
+            "origin"
+        );
+        assert!(review_remote(&remotes, &hosts, "bob", "git@github.com:bob/repo.git").is_err());
+    }
+
+    #[test]
+    fn test_issue_branch_name() {
+        assert_eq!(
+            issue_branch_name("{issue}-{name}", 1234, "thing"),
+            "1234-thing"
+        );
+        assert_eq!(
+            issue_branch_name("fix/{name}-{issue}", 7, "crash"),
+            "fix/crash-7"
+        );
+    }
+
+    #[test]
+    fn test_is_host() {
+        let github = ["github.com".to_string()];
+        let aliases = ["github-work".to_string()];
+        assert!(is_host("github.com", &github, &[]));
+        assert!(is_host("ssh.github.com", &github, &[]));
+        assert!(is_host("github-work", &github, &aliases));
+        assert!(!is_host("github-work", &github, &[]));
+        assert!(!is_host("notgithub.com", &github, &aliases));
+        let github = ["github.com".to_string(), "github.mycorp.com".to_string()];
+        assert!(is_host("github.com", &github, &[]));
+        assert!(is_host("github.mycorp.com", &github, &[]));
+    }
+
+    #[test]
+    fn test_gitlab_project() {
+        for (url, expected) in &[
+            ("git@gitlab.com:group/project.git", "group/project"),
+            ("git@gitlab.com:group/project", "group/project"),
+            (
+                "git@gitlab.com:group/subgroup/project.git",
+                "group/subgroup/project",
+            ),
+            ("https://gitlab.com/group/project.git", "group/project"),
+            (
+                "https://gitlab.com/group/subgroup/project",
+                "group/subgroup/project",
+            ),
+            (
+                "https://gitlab.com/group/subgroup/project/",
+                "group/subgroup/project",
+            ),
+            (
+                "ssh://git@gitlab.com:2222/group/subgroup/project.git",
+                "group/subgroup/project",
+            ),
+        ] {
+            let remote = Remote {
+                url: url.to_string(),
+            };
+            match remote.repository() {
+                RepositoryType::GitLab(g) => assert_eq!(g.project(), *expected),
+                other => panic!("{} is not GitLab: {:?}", url, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_bitbucket_repository() {
+        let expected = bitbucket::RepoId {
+            workspace: "workspace".to_string(),
+            slug: "repo".to_string(),
+        };
+        for url in &[
+            "git@bitbucket.org:workspace/repo.git",
+            "https://bitbucket.org/workspace/repo.git",
+            "https://user@bitbucket.org/workspace/repo.git",
+        ] {
+            let remote = Remote {
+                url: url.to_string(),
+            };
+            match remote.repository() {
+                RepositoryType::Bitbucket(b) => assert_eq!(b.repository(), expected),
+                other => panic!("{} is not Bitbucket: {:?}", url, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_formatter_config() {
+        let config = FormatterConfig::from_toml(
+            r#"
+            [fix]
+            "*.rs" = "rustfmt {path}"
+            "web/*.ts" = "prettier --write"
+            "*.cc" = { command = "clang-format -i", timeout = 5 }
+            "*" = "cat {path}"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.formatter_for(Path::new("src/main.rs")),
+            Some(&Formatter::new("rustfmt {path}"))
+        );
+        assert_eq!(
+            config.formatter_for(Path::new("web/app.ts")),
+            Some(&Formatter::new("prettier --write"))
+        );
+        assert_eq!(
+            config.formatter_for(Path::new("lib/app.ts")),
+            Some(&Formatter::new("cat {path}"))
+        );
+        assert_eq!(
+            config.formatter_for(Path::new("src/a.cc")),
+            Some(&Formatter {
+                command: "clang-format -i".to_string(),
+                timeout: Duration::from_secs(5),
+            })
+        );
+
+        let defaults = FormatterConfig::from_toml("[other]\nkey = 1").unwrap();
+        assert_eq!(defaults, FormatterConfig::default());
+        assert_eq!(defaults.formatter_for(Path::new("src/main.rs")), None);
+        assert_eq!(
+            defaults.formatter_for(Path::new("foo/BUILD")),
+            Some(&Formatter::new("buildifier {path}"))
+        );
+
+        assert!(FormatterConfig::from_toml("[fix]\n\"*.rs\" = 1").is_err());
+        assert!(FormatterConfig::from_toml("[fix]\n\"*.rs\" = { timeout = 5 }").is_err());
+        assert!(FormatterConfig::from_toml("[fix]\n\"*.rs\" = \"  \"").is_err());
+        assert!(FormatterConfig::from_toml("[fix]\n\"*.rs\" = { command = \"\" }").is_err());
+        assert!(
+            FormatterConfig::from_toml("[fix]\n\"*.rs\" = { command = \"a\", timeout = 0 }")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_clone_target() {
+        let target = |d: &str, r: &str| Some((d.to_string(), r.to_string()));
+        assert_eq!(
+            clone_target(&["git@github.com:SirVer/giti.git"]),
+            target("giti", "origin")
+        );
+        assert_eq!(
+            clone_target(&["https://github.com/SirVer/giti/", "--depth", "1"]),
+            target("giti", "origin")
+        );
+        assert_eq!(
+            clone_target(&["-o", "upstream", "-b", "dev", "/tmp/repo.git", "work"]),
+            target("work", "upstream")
+        );
+        assert_eq!(
+            clone_target(&["--origin=up", "--", "../repo"]),
+            target("repo", "up")
+        );
+        assert_eq!(clone_target(&["--bare", "../repo"]), None);
+        assert_eq!(clone_target(&[]), None);
+    }
+
+    #[test]
+    fn test_formatter_command() {
+        assert_eq!(
+            formatter_command("black -q {path}", Path::new("/r/a.py")),
+            ["black", "-q", "/r/a.py"]
+        );
+        assert_eq!(
+            formatter_command("prettier --write", Path::new("/r/a.ts")),
+            ["prettier", "--write", "/r/a.ts"]
+        );
+    }
+
+    #[test]
+    fn test_parse_template() {
+        let parts = parse_template("{repo}#{number}: {title}").unwrap();
+        assert_eq!(
+            parts,
+            [
+                TemplatePart::Field("repo".to_string()),
+                TemplatePart::Literal("#".to_string()),
+                TemplatePart::Field("number".to_string()),
+                TemplatePart::Literal(": ".to_string()),
+                TemplatePart::Field("title".to_string()),
+            ]
+        );
+        assert!(parse_template("{repo} {blub}").is_err());
+        assert!(parse_template("{repo").is_err());
+    }
+}
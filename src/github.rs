@@ -1,11 +1,14 @@
+use crate::config;
 use crate::error::*;
-use chrono::{DateTime, Local};
-use futures::StreamExt;
+use chrono::{DateTime, Local, TimeZone};
+use futures::{StreamExt, TryStreamExt};
 use hubcaps_ex::search::SearchIssuesOptions;
 use hubcaps_ex::{self, Credentials};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env;
 use std::fmt::Display;
+use std::io::{self, IsTerminal};
 use std::path::Path;
 use std::str::FromStr;
 
@@ -36,7 +39,9 @@ impl Branch {
 #[derive(Debug, PartialEq, Eq)]
 pub enum PullRequestState {
     Open,
+    // Closed without being merged.
     Closed,
+    Merged,
 }
 
 impl FromStr for PullRequestState {
@@ -58,10 +63,49 @@ pub struct PullRequest {
     pub number: i32,
     pub author_login: String,
     pub title: String,
+    pub body: String,
     pub state: PullRequestState,
+    // RFC 3339 timestamp of when the PR was opened.
+    pub created_at: String,
+    // The size of the diff. Only the full pull has these, not the ones in lists.
+    pub additions: Option<u64>,
+    pub deletions: Option<u64>,
+    pub changed_files: Option<u64>,
+    // The commit the source branch was at when the PR was fetched.
+    pub head_sha: String,
 }
 
 impl PullRequest {
+    fn from_pull(repo_name: &str, pr: &hubcaps_ex::pulls::Pull) -> Self {
+        PullRequest {
+            source: Branch::from_label(repo_name, &pr.head.label),
+            target: Branch::from_label(repo_name, &pr.base.label),
+            number: pr.number as i32,
+            author_login: pr.user.login.clone(),
+            title: pr.title.clone(),
+            body: pr.body.clone().unwrap_or_default(),
+            // The API reports merged PRs as 'closed'. Only single PRs carry 'merged', lists just
+            // 'merged_at'.
+            state: if pr.merged || pr.merged_at.is_some() {
+                PullRequestState::Merged
+            } else {
+                PullRequestState::from_str(&pr.state).unwrap()
+            },
+            created_at: pr.created_at.clone(),
+            additions: pr.additions,
+            deletions: pr.deletions,
+            changed_files: pr.changed_files,
+            head_sha: pr.head.sha.clone(),
+        }
+    }
+
+    /// Returns the size of the diff like '+120 -34, 5 files', if it is known.
+    pub fn diff_stats(&self) -> Option<String> {
+        let (additions, deletions, files) = (self.additions?, self.deletions?, self.changed_files?);
+        let unit = if files == 1 { "file" } else { "files" };
+        Some(format!("+{additions} -{deletions}, {files} {unit}"))
+    }
+
     pub fn id(&self) -> PullRequestId {
         PullRequestId {
             repo: self.target.repo.clone(),
@@ -80,8 +124,11 @@ pub struct PullRequestId {
 impl PullRequestId {
     pub fn url(&self) -> String {
         format!(
-            "https://github.com/{}/{}/pull/{}",
-            self.repo.owner, self.repo.name, self.number
+            "https://{}/{}/{}/pull/{}",
+            host(),
+            self.repo.owner,
+            self.repo.name,
+            self.number
         )
     }
 }
@@ -104,6 +151,89 @@ pub struct RepoId {
 
 type Github = hubcaps_ex::Github;
 
+const USER_AGENT: &str = "SirVer_giti/unspecified";
+
+const DEFAULT_GITHUB_HOST: &str = "github.com";
+
+/// Returns the host of the GitHub instance to talk to, e.g. 'github.mycorp.com' for GitHub
+/// Enterprise. It is read from $GITHUB_HOST or giti.github.host, and defaults to github.com.
+pub fn host() -> String {
+    env::var("GITHUB_HOST")
+        .ok()
+        .into_iter()
+        .chain(config::get_string("giti.github.host"))
+        .map(|h| h.trim().trim_end_matches('/').to_string())
+        .find(|h| !h.is_empty())
+        .unwrap_or_else(|| DEFAULT_GITHUB_HOST.to_string())
+}
+
+/// Returns the hosts whose remotes are GitHub repositories: github.com and, if configured, the
+/// GitHub Enterprise host.
+pub fn hosts() -> Vec<String> {
+    let mut hosts = vec![DEFAULT_GITHUB_HOST.to_string()];
+    let host = host();
+    if host != DEFAULT_GITHUB_HOST {
+        hosts.push(host);
+    }
+    hosts
+}
+
+/// Returns the URL of the REST API 'endpoint' on 'host'. github.com serves its API from a
+/// subdomain, GitHub Enterprise under /api/v3, except for GraphQL which lives at /api/graphql.
+fn api_url(host: &str, endpoint: &str) -> String {
+    if host == DEFAULT_GITHUB_HOST {
+        format!("https://api.github.com/{endpoint}")
+    } else if endpoint == "graphql" {
+        format!("https://{host}/api/graphql")
+    } else {
+        format!("https://{host}/api/v3/{endpoint}")
+    }
+}
+
+/// The kind of token in GITHUB_TOKEN. Set GITHUB_TOKEN_KIND or giti.github.tokenKind to
+/// 'installation' for the token of a GitHub App installation, e.g. in CI.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum TokenKind {
+    Personal,
+    Installation,
+}
+
+fn token_kind() -> TokenKind {
+    let kind = env::var("GITHUB_TOKEN_KIND")
+        .ok()
+        .or_else(|| config::get_string("giti.github.tokenKind"));
+    match kind.as_deref() {
+        Some("installation") => TokenKind::Installation,
+        _ => TokenKind::Personal,
+    }
+}
+
+/// Returns a client that authenticates with GITHUB_TOKEN. An already issued installation token is
+/// sent in the Authorization header just like a personal one, so both use the same credentials.
+fn client() -> Result<Github> {
+    let token = env::var("GITHUB_TOKEN")?;
+    // hubcaps appends paths starting with '/' to the base URL.
+    let base_url = api_url(&host(), "");
+    Ok(Github::host(
+        base_url.trim_end_matches('/'),
+        USER_AGENT,
+        Some(Credentials::Token(token)),
+    )?)
+}
+
+/// Installation tokens act as the app, not as a user, so everything that is about 'me' needs a
+/// personal token.
+fn expect_user_token() -> Result<()> {
+    match token_kind() {
+        TokenKind::Personal => Ok(()),
+        TokenKind::Installation => Err(Error::general(
+            "GITHUB_TOKEN is an installation token, which does not belong to a user. \
+             This needs a personal access token."
+                .to_string(),
+        )),
+    }
+}
+
 // bug fixed version from hubcaps: http://lessis.me/hubcaps/src/hubcaps/search/mod.rs.html#229-235
 pub fn repo_tuple(repository_url: &str) -> (String, String) {
     // split the last two elements off the repo url path
@@ -126,30 +256,56 @@ async fn fetch_pr(
     Ok((pr_id.repo, res))
 }
 
+/// How many PRs are fetched at the same time, so that long lists do not trip GitHub's limits.
+const MAX_CONCURRENT_FETCHES: usize = 10;
+
+/// Fetches all 'pr_ids', keeping their order. The progress is shown on stderr if it is a terminal,
+/// since stdout might be parsed.
+async fn fetch_prs(
+    github: &Github,
+    pr_ids: Vec<PullRequestId>,
+) -> hubcaps_ex::Result<Vec<(RepoId, hubcaps_ex::pulls::Pull)>> {
+    let total = pr_ids.len();
+    let show_progress = total > 1 && io::stderr().is_terminal();
+    let mut fetched = 0;
+    let prs = futures::stream::iter(pr_ids)
+        .map(|pr_id| fetch_pr(github.clone(), pr_id))
+        .buffered(MAX_CONCURRENT_FETCHES)
+        .inspect_ok(|_| {
+            fetched += 1;
+            if show_progress {
+                eprint!("\rFetched {}/{}", fetched, total);
+            }
+        })
+        .try_collect()
+        .await;
+    if show_progress && fetched > 0 {
+        eprintln!();
+    }
+    prs
+}
+
 async fn search_prs(
     github: Github,
     query: String,
 ) -> hubcaps_ex::Result<Vec<(RepoId, hubcaps_ex::pulls::Pull)>> {
+    // The iterator follows the pages of the search results. Any page that fails must fail the
+    // search, or the results are silently cut short.
     let mut search = github
         .search()
         .issues()
-        .iter(query, &SearchIssuesOptions::builder().per_page(25).build());
+        .iter(query, &SearchIssuesOptions::builder().per_page(100).build());
 
-    let mut futures = vec![];
-    while let Some(Ok(result)) = search.next().await {
+    let mut pr_ids = vec![];
+    while let Some(result) = search.next().await {
+        let result = result?;
         let (owner, name) = repo_tuple(&result.repository_url);
-        let pr_id = PullRequestId {
+        pr_ids.push(PullRequestId {
             repo: RepoId { owner, name },
             number: result.number as i32,
-        };
-        futures.push(fetch_pr(github.clone(), pr_id));
+        });
     }
-
-    let mut results = vec![];
-    for rv in futures::future::join_all(futures).await {
-        results.push(rv?);
-    }
-    Ok(results)
+    fetch_prs(&github, pr_ids).await
 }
 
 async fn find_login_name(github: Github) -> hubcaps_ex::Result<String> {
@@ -167,27 +323,19 @@ async fn run_find_assigned_prs(
 
 fn search_result_to_pull_requests(prs: Vec<(RepoId, hubcaps_ex::pulls::Pull)>) -> Vec<PullRequest> {
     prs.iter()
-        .map(|(pr_repo, pr)| PullRequest {
-            source: Branch::from_label(&pr_repo.name, &pr.head.label),
-            target: Branch::from_label(&pr_repo.name, &pr.base.label),
-            number: pr.number as i32,
-            author_login: pr.user.login.clone(),
-            title: pr.title.clone(),
-            state: PullRequestState::from_str(&pr.state).unwrap(),
-        })
+        .map(|(pr_repo, pr)| PullRequest::from_pull(&pr_repo.name, pr))
         .collect()
 }
 
 pub async fn find_assigned_prs(repo: Option<&RepoId>) -> Result<Vec<PullRequest>> {
-    let token = env::var("GITHUB_TOKEN")?;
+    expect_user_token()?;
+    let github = client()?;
     let repo = repo.cloned();
 
     async move {
-        let github = Github::new("SirVer_giti/unspecified", Some(Credentials::Token(token)))
-            .expect("GitHub could not be constructed");
         let mut prs = run_find_assigned_prs(github.clone())
             .await
-            .expect("run_find_assigned_prs() did not succeed.");
+            .map_err(github_error)?;
         prs.sort_by_key(|(_, pr)| pr.number);
 
         let new_result = search_result_to_pull_requests(
@@ -204,16 +352,43 @@ pub async fn find_assigned_prs(repo: Option<&RepoId>) -> Result<Vec<PullRequest>
     .await
 }
 
-pub async fn find_my_prs(start: DateTime<Local>, end: DateTime<Local>) -> Result<Vec<PullRequest>> {
-    let token = env::var("GITHUB_TOKEN")?;
+/// Finds the open PRs in 'repo' that the authenticated user opened.
+pub async fn find_open_authored_prs(repo: &RepoId) -> Result<Vec<PullRequest>> {
+    expect_user_token()?;
+    let github = client()?;
+    let login = find_login_name(github.clone())
+        .await
+        .map_err(github_error)?;
+    let query = format!(
+        "is:pr is:open archived:false author:{} repo:{}/{}",
+        login, repo.owner, repo.name
+    );
+    let mut prs =
+        search_result_to_pull_requests(search_prs(github, query).await.map_err(github_error)?);
+    prs.sort_by_key(|pr| pr.number);
+    Ok(prs)
+}
 
-    async move {
-        let github = Github::new("SirVer_giti/unspecified", Some(Credentials::Token(token)))
-            .expect("GitHub could not be constructed");
+/// Finds the PRs opened by 'author' between 'start' and 'end'. Without an 'author', these are the
+/// PRs of the authenticated user.
+pub async fn find_my_prs(
+    start: DateTime<Local>,
+    end: DateTime<Local>,
+    author: Option<&str>,
+) -> Result<Vec<PullRequest>> {
+    if author.is_none() {
+        expect_user_token()?;
+    }
+    let github = client()?;
+    let author = author.map(|a| a.to_string());
 
-        let login = find_login_name(github.clone())
-            .await
-            .expect("Could not find GitHub login.");
+    async move {
+        let login = match author {
+            Some(author) => author,
+            None => find_login_name(github.clone())
+                .await
+                .map_err(github_error)?,
+        };
         let query = format!(
             "is:pr author:{} created:{}..{}",
             login,
@@ -222,7 +397,7 @@ pub async fn find_my_prs(start: DateTime<Local>, end: DateTime<Local>) -> Result
         );
         let prs = search_prs(github.clone(), query)
             .await
-            .expect("Could not search for PRs.");
+            .map_err(github_error)?;
 
         let mut results = search_result_to_pull_requests(prs);
         results.sort_by_key(|pr| (pr.target.repo.name.clone(), pr.number));
@@ -231,56 +406,433 @@ pub async fn find_my_prs(start: DateTime<Local>, end: DateTime<Local>) -> Result
     .await
 }
 
+/// Opens a pull request. hubcaps cannot open drafts, so those are created through the REST API
+/// directly.
 pub async fn create_pr(
     repo: &RepoId,
     pull_options: hubcaps_ex::pulls::PullOptions,
+    draft: bool,
 ) -> Result<PullRequest> {
-    let token = env::var("GITHUB_TOKEN")?;
+    if draft {
+        let mut body = serde_json::to_value(&pull_options)?;
+        body["draft"] = serde_json::Value::Bool(true);
+        let pr: hubcaps_ex::pulls::Pull = send_json(
+            api_request(
+                reqwest::Method::POST,
+                &format!("repos/{}/{}/pulls", repo.owner, repo.name),
+            )?
+            .json(&body),
+        )
+        .await?;
+        return Ok(PullRequest::from_pull(&repo.name, &pr));
+    }
+
+    let github = client()?;
 
     let repo_clone = repo.clone();
     let pr = async move {
-        let github = Github::new("SirVer_giti/unspecified", Some(Credentials::Token(token)))
-            .expect("GitHub could not be constructed");
-
         github
             .repo(repo_clone.owner.to_string(), repo_clone.name.to_string())
             .pulls()
             .create(&pull_options)
             .await
     }
-    .await?;
+    .await
+    .map_err(github_error)?;
 
-    Ok(PullRequest {
-        source: Branch::from_label(&repo.name, &pr.head.label),
-        target: Branch::from_label(&repo.name, &pr.base.label),
-        number: pr.number as i32,
-        author_login: pr.user.login.clone(),
-        title: pr.title.clone(),
-        state: PullRequestState::from_str(&pr.state).unwrap(),
-    })
+    Ok(PullRequest::from_pull(&repo.name, &pr))
+}
+
+/// Returns the login of the user the token belongs to.
+pub async fn get_login() -> Result<String> {
+    expect_user_token()?;
+    find_login_name(client()?).await.map_err(github_error)
+}
+
+pub async fn add_assignees(pr_id: &PullRequestId, logins: &[String]) -> Result<()> {
+    let github = client()?;
+    github
+        .repo(pr_id.repo.owner.to_string(), pr_id.repo.name.to_string())
+        .pulls()
+        .get(pr_id.number as u64)
+        .assignees()
+        .add(logins.iter().map(|l| l as &str).collect())
+        .await
+        .map_err(github_error)?;
+    Ok(())
+}
+
+pub async fn request_reviewers(pr_id: &PullRequestId, logins: &[String]) -> Result<()> {
+    let github = client()?;
+    github
+        .repo(pr_id.repo.owner.to_string(), pr_id.repo.name.to_string())
+        .pulls()
+        .get(pr_id.number as u64)
+        .review_requests()
+        .create(&hubcaps_ex::review_requests::ReviewRequestOptions {
+            reviewers: logins.to_vec(),
+            team_reviewers: Vec::new(),
+        })
+        .await
+        .map_err(github_error)?;
+    Ok(())
+}
+
+pub async fn update_pr(pr_id: &PullRequestId, title: &str, body: &str) -> Result<()> {
+    let github = client()?;
+    github
+        .repo(pr_id.repo.owner.to_string(), pr_id.repo.name.to_string())
+        .pulls()
+        .get(pr_id.number as u64)
+        .edit(
+            &hubcaps_ex::pulls::PullEditOptions::builder()
+                .title(title)
+                .body(body)
+                .build(),
+        )
+        .await
+        .map_err(github_error)?;
+    Ok(())
+}
+
+/// Returns the logins of all members of the team 'org/team_slug'.
+pub async fn get_team_members(org: &str, team_slug: &str) -> Result<Vec<String>> {
+    let github = client()?;
+    let teams = github.org(org).teams().list().await.map_err(github_error)?;
+    let team = teams
+        .iter()
+        .find(|t| t.slug == team_slug)
+        .ok_or_else(|| Error::general(format!("Team {}/{} does not exist.", org, team_slug)))?;
+    let members = github
+        .org(org)
+        .teams()
+        .get(team.id)
+        .list_members()
+        .await
+        .map_err(github_error)?;
+    Ok(members.into_iter().map(|m| m.login).collect())
 }
 
 pub async fn get_pr(pr_id: &PullRequestId) -> Result<PullRequest> {
-    let token = env::var("GITHUB_TOKEN")?;
+    let github = client()?;
 
     let pr_id_clone = pr_id.clone();
     let pr = async move {
-        let github = Github::new("SirVer_giti/unspecified", Some(Credentials::Token(token)))
-            .expect("GitHub could not be constructed");
-        let (_, pr) = fetch_pr(github, pr_id_clone)
-            .await
-            .expect("fetch_pr did not complete.");
-        pr
-    }
-    .await;
-
-    Ok(PullRequest {
-        source: Branch::from_label(&pr_id.repo.name, &pr.head.label),
-        target: Branch::from_label(&pr_id.repo.name, &pr.base.label),
-        number: pr.number as i32,
-        author_login: pr.user.login.clone(),
-        title: pr.title.clone(),
-        state: PullRequestState::from_str(&pr.state).unwrap(),
+        let (_, pr) = fetch_pr(github, pr_id_clone).await?;
+        Ok(pr)
+    }
+    .await
+    .map_err(github_error)?;
+
+    Ok(PullRequest::from_pull(&pr_id.repo.name, &pr))
+}
+
+/// Fetches all 'pr_ids' with a single client.
+pub async fn get_prs(pr_ids: &[PullRequestId]) -> Result<Vec<PullRequest>> {
+    let github = client()?;
+    Ok(fetch_prs(&github, pr_ids.to_vec())
+        .await
+        .map_err(github_error)?
+        .iter()
+        .map(|(repo, pr)| PullRequest::from_pull(&repo.name, pr))
+        .collect())
+}
+
+/// Returns the error for running out of API requests, telling when they are available again.
+/// 'reset' is the unix time at which the limit resets, if GitHub told us.
+fn rate_limit_error(reset: Option<i64>) -> Error {
+    let reset = reset.and_then(|r| Local.timestamp_opt(r, 0).single());
+    match reset {
+        Some(reset) => Error::general(format!(
+            "GitHub's API rate limit is exceeded. It resets at {}, try again then.",
+            reset.format("%H:%M:%S")
+        )),
+        None => Error::general(
+            "GitHub's API rate limit is exceeded. Wait a few minutes and try again.".to_string(),
+        ),
+    }
+}
+
+/// Returns true if GitHub refused a request with 'status' and 'message' because of a rate limit.
+/// Secondary rate limits do not set X-RateLimit-Remaining, so the message is checked too.
+fn is_rate_limited(status: u16, remaining: Option<&str>, message: &str) -> bool {
+    matches!(status, 403 | 429)
+        && (remaining == Some("0") || message.to_lowercase().contains("rate limit"))
+}
+
+/// Turns a hubcaps error into an Error, with a clear message if a rate limit was hit.
+fn github_error(err: hubcaps_ex::Error) -> Error {
+    match err {
+        hubcaps_ex::Error::RateLimit { reset } => {
+            rate_limit_error(Some(Local::now().timestamp() + reset.as_secs() as i64))
+        }
+        hubcaps_ex::Error::Fault { code, ref error }
+            if is_rate_limited(code.as_u16(), None, &error.message) =>
+        {
+            rate_limit_error(None)
+        }
+        err => Error::from(err),
+    }
+}
+
+/// Sends 'request' and parses the JSON response. Failures are errors, with a clear message if a
+/// rate limit was hit.
+async fn send_json<T: serde::de::DeserializeOwned>(request: reqwest::RequestBuilder) -> Result<T> {
+    let response = request.send().await?;
+    let status_error = match response.error_for_status_ref() {
+        Ok(_) => return Ok(response.json().await?),
+        Err(err) => err,
+    };
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let remaining = header("x-ratelimit-remaining");
+    let reset = header("x-ratelimit-reset").and_then(|r| r.parse::<i64>().ok());
+    let status = response.status().as_u16();
+    let message = response.text().await.unwrap_or_default();
+    if is_rate_limited(status, remaining.as_deref(), &message) {
+        return Err(rate_limit_error(reset));
+    }
+    Err(status_error.into())
+}
+
+/// Returns a request to the REST API 'endpoint', for what hubcaps does not support.
+fn api_request(method: reqwest::Method, endpoint: &str) -> Result<reqwest::RequestBuilder> {
+    let token = env::var("GITHUB_TOKEN")?;
+    Ok(reqwest::Client::new()
+        .request(method, api_url(&host(), endpoint))
+        .header("User-Agent", USER_AGENT)
+        .header("Accept", "application/vnd.github+json")
+        .bearer_auth(token))
+}
+
+#[derive(Deserialize, Debug)]
+struct DraftJson {
+    node_id: String,
+    draft: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct GraphQlErrorJson {
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GraphQlResponseJson {
+    #[serde(default)]
+    errors: Vec<GraphQlErrorJson>,
+}
+
+/// Marks the draft 'pr_id' as ready for review. Does nothing if it is not a draft. Neither hubcaps
+/// nor the REST API can do this, only the GraphQL API.
+pub async fn mark_ready_for_review(pr_id: &PullRequestId) -> Result<()> {
+    let pr: DraftJson = send_json(api_request(
+        reqwest::Method::GET,
+        &format!(
+            "repos/{}/{}/pulls/{}",
+            pr_id.repo.owner, pr_id.repo.name, pr_id.number
+        ),
+    )?)
+    .await?;
+    if !pr.draft {
+        return Ok(());
+    }
+
+    let query = serde_json::json!({
+        "query": "mutation($id: ID!) { \
+                  markPullRequestReadyForReview(input: {pullRequestId: $id}) { clientMutationId } }",
+        "variables": { "id": pr.node_id },
+    });
+    let response: GraphQlResponseJson =
+        send_json(api_request(reqwest::Method::POST, "graphql")?.json(&query)).await?;
+    match response.errors.first() {
+        None => Ok(()),
+        Some(err) => Err(Error::general(format!(
+            "Could not mark {} as ready: {}",
+            pr_id, err.message
+        ))),
+    }
+}
+
+pub async fn add_labels(pr_id: &PullRequestId, labels: &[String]) -> Result<()> {
+    let github = client()?;
+    github
+        .repo(pr_id.repo.owner.to_string(), pr_id.repo.name.to_string())
+        .issues()
+        .get(pr_id.number as u64)
+        .labels()
+        .add(labels.iter().map(|l| l as &str).collect())
+        .await
+        .map_err(github_error)?;
+    Ok(())
+}
+
+/// Removes those of 'labels' that are set on 'pr_id'.
+pub async fn remove_labels(pr_id: &PullRequestId, labels: &[String]) -> Result<()> {
+    let github = client()?;
+    let issue = github
+        .repo(pr_id.repo.owner.to_string(), pr_id.repo.name.to_string())
+        .issues()
+        .get(pr_id.number as u64);
+    let present = issue.get().await.map_err(github_error)?.labels;
+    for label in labels {
+        if present.iter().any(|l| l.name == *label) {
+            issue.labels().remove(label).await.map_err(github_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// The summary of the commit statuses and check runs of a PR.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ChecksState {
+    // All checks succeeded, or there are none.
+    Passing,
+    Failing,
+    Pending,
+}
+
+#[derive(Deserialize, Debug)]
+struct CombinedStatusJson {
+    state: String,
+    total_count: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct CheckRunJson {
+    status: String,
+    conclusion: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CheckRunsJson {
+    check_runs: Vec<CheckRunJson>,
+}
+
+/// Combines the commit statuses and the check runs into one state. Any failure fails, and
+/// otherwise anything unfinished is pending.
+fn summarize_checks(status: &CombinedStatusJson, check_runs: &[CheckRunJson]) -> ChecksState {
+    let status_failed = matches!(status.state.as_str(), "failure" | "error");
+    let run_failed = check_runs.iter().any(|r| {
+        matches!(
+            r.conclusion.as_deref(),
+            Some("failure" | "timed_out" | "cancelled" | "action_required")
+        )
+    });
+    if status_failed || run_failed {
+        return ChecksState::Failing;
+    }
+    // Without any statuses, GitHub reports the combined state as 'pending'.
+    let status_pending = status.total_count > 0 && status.state == "pending";
+    let run_pending = check_runs.iter().any(|r| r.status != "completed");
+    if status_pending || run_pending {
+        ChecksState::Pending
+    } else {
+        ChecksState::Passing
+    }
+}
+
+/// Returns the state of the commit statuses and the check runs of 'pr_id' at 'head_sha'. hubcaps
+/// does not know about check runs, so this talks to the REST API directly.
+pub async fn get_pr_checks(pr_id: &PullRequestId, head_sha: &str) -> Result<ChecksState> {
+    let commit = format!(
+        "repos/{}/{}/commits/{}",
+        pr_id.repo.owner, pr_id.repo.name, head_sha
+    );
+    let status: CombinedStatusJson = send_json(api_request(
+        reqwest::Method::GET,
+        &format!("{}/status", commit),
+    )?)
+    .await?;
+    let check_runs: CheckRunsJson = send_json(api_request(
+        reqwest::Method::GET,
+        &format!("{}/check-runs?per_page=100", commit),
+    )?)
+    .await?;
+    Ok(summarize_checks(&status, &check_runs.check_runs))
+}
+
+#[derive(Deserialize, Debug)]
+struct ReviewUserJson {
+    login: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ReviewJson {
+    user: Option<ReviewUserJson>,
+    state: String,
+}
+
+/// Returns true if a reviewer's latest review of 'pr_id' requests changes. Comments do not count
+/// as a review decision.
+pub async fn has_requested_changes(pr_id: &PullRequestId) -> Result<bool> {
+    let reviews: Vec<ReviewJson> = send_json(api_request(
+        reqwest::Method::GET,
+        &format!(
+            "repos/{}/{}/pulls/{}/reviews?per_page=100",
+            pr_id.repo.owner, pr_id.repo.name, pr_id.number
+        ),
+    )?)
+    .await?;
+    // The reviews are in chronological order, so later decisions replace earlier ones.
+    let mut decisions = HashMap::new();
+    for review in &reviews {
+        if let (Some(user), "APPROVED" | "CHANGES_REQUESTED" | "DISMISSED") =
+            (&review.user, review.state.as_str())
+        {
+            decisions.insert(&user.login, &review.state);
+        }
+    }
+    Ok(decisions.values().any(|s| *s == "CHANGES_REQUESTED"))
+}
+
+/// The parts of a branch's protection rules that are relevant for opening a pull request.
+#[derive(Debug)]
+pub struct BranchProtection {
+    pub required_approvals: Option<u64>,
+    pub status_checks: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RequiredReviewsJson {
+    required_approving_review_count: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct StatusChecksJson {
+    #[serde(default)]
+    contexts: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BranchProtectionJson {
+    required_pull_request_reviews: Option<RequiredReviewsJson>,
+    required_status_checks: Option<StatusChecksJson>,
+}
+
+/// Fetches the protection rules of 'branch'. hubcaps can only set them, so this talks to the REST
+/// API directly. Requires a token that has admin rights on the repository.
+pub async fn get_branch_protection(repo: &RepoId, branch: &str) -> Result<BranchProtection> {
+    let json: BranchProtectionJson = send_json(api_request(
+        reqwest::Method::GET,
+        &format!(
+            "repos/{}/{}/branches/{}/protection",
+            repo.owner, repo.name, branch
+        ),
+    )?)
+    .await?;
+    Ok(BranchProtection {
+        required_approvals: json
+            .required_pull_request_reviews
+            .and_then(|r| r.required_approving_review_count),
+        status_checks: json
+            .required_status_checks
+            .map(|c| c.contexts)
+            .unwrap_or_default(),
     })
 }
 
@@ -307,3 +859,100 @@ pub fn get_pull_request_template(workdir: &Path) -> Option<String> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        api_url, is_rate_limited, summarize_checks, CheckRunJson, ChecksState, CombinedStatusJson,
+    };
+
+    #[test]
+    fn test_api_url() {
+        assert_eq!(
+            api_url("github.com", "repos/a/b"),
+            "https://api.github.com/repos/a/b"
+        );
+        assert_eq!(
+            api_url("github.com", "graphql"),
+            "https://api.github.com/graphql"
+        );
+        assert_eq!(
+            api_url("github.mycorp.com", "repos/a/b"),
+            "https://github.mycorp.com/api/v3/repos/a/b"
+        );
+        assert_eq!(
+            api_url("github.mycorp.com", "graphql"),
+            "https://github.mycorp.com/api/graphql"
+        );
+        assert_eq!(
+            api_url("github.mycorp.com", ""),
+            "https://github.mycorp.com/api/v3/"
+        );
+    }
+
+    #[test]
+    fn test_is_rate_limited() {
+        assert!(is_rate_limited(403, Some("0"), "Forbidden"));
+        assert!(is_rate_limited(
+            403,
+            Some("12"),
+            "You have exceeded a secondary rate limit."
+        ));
+        assert!(is_rate_limited(
+            429,
+            None,
+            "API rate limit exceeded for user."
+        ));
+        assert!(!is_rate_limited(
+            403,
+            Some("12"),
+            "Resource not accessible."
+        ));
+        assert!(!is_rate_limited(404, Some("0"), "Not Found"));
+    }
+
+    #[test]
+    fn test_summarize_checks() {
+        let status = |state: &str, total_count| CombinedStatusJson {
+            state: state.to_string(),
+            total_count,
+        };
+        let run = |status: &str, conclusion: Option<&str>| CheckRunJson {
+            status: status.to_string(),
+            conclusion: conclusion.map(|c| c.to_string()),
+        };
+        assert_eq!(
+            summarize_checks(&status("pending", 0), &[]),
+            ChecksState::Passing
+        );
+        assert_eq!(
+            summarize_checks(&status("pending", 1), &[]),
+            ChecksState::Pending
+        );
+        assert_eq!(
+            summarize_checks(&status("success", 1), &[run("in_progress", None)]),
+            ChecksState::Pending
+        );
+        assert_eq!(
+            summarize_checks(
+                &status("pending", 1),
+                &[run("completed", Some("failure")), run("queued", None)]
+            ),
+            ChecksState::Failing
+        );
+        assert_eq!(
+            summarize_checks(&status("error", 2), &[]),
+            ChecksState::Failing
+        );
+        assert_eq!(
+            summarize_checks(
+                &status("success", 2),
+                &[
+                    run("completed", Some("success")),
+                    run("completed", Some("skipped"))
+                ]
+            ),
+            ChecksState::Passing
+        );
+    }
+}
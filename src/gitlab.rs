@@ -1,11 +1,52 @@
+use crate::config;
 use crate::error::*;
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::env;
+use std::path::Path;
+use std::time::Duration;
 use url::form_urlencoded;
 
-const GITLAB_BASE_URL: &str = "https://gitlab.com/api/v4";
+const DEFAULT_GITLAB_HOST: &str = "gitlab.com";
+
+/// How often a failed GET is retried, and how long to wait before the first retry. The wait
+/// doubles with every retry.
+const MAX_RETRIES: u32 = 3;
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Returns the host of the GitLab instance to talk to, e.g. 'gitlab.internal.corp'. It is read
+/// from $GITLAB_HOST or giti.gitlab.host, and defaults to gitlab.com.
+pub fn host() -> String {
+    resolve_host(
+        env::var("GITLAB_HOST").ok(),
+        config::get_string("giti.gitlab.host"),
+    )
+}
+
+/// Returns the hosts whose remotes are GitLab repositories: gitlab.com and, if configured, the
+/// self-hosted instance.
+pub fn hosts() -> Vec<String> {
+    let mut hosts = vec![DEFAULT_GITLAB_HOST.to_string()];
+    let host = host();
+    if host != DEFAULT_GITLAB_HOST {
+        hosts.push(host);
+    }
+    hosts
+}
+
+fn resolve_host(from_env: Option<String>, from_config: Option<String>) -> String {
+    from_env
+        .into_iter()
+        .chain(from_config)
+        .map(|h| h.trim().trim_end_matches('/').to_string())
+        .find(|h| !h.is_empty())
+        .unwrap_or_else(|| DEFAULT_GITLAB_HOST.to_string())
+}
+
+fn api_base_url(host: &str) -> String {
+    format!("https://{host}/api/v4")
+}
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
 pub enum PullRequestState {
@@ -20,6 +61,8 @@ pub enum PullRequestState {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MergeRequest {
     pub title: String,
+    #[serde(default)]
+    pub description: Option<String>,
     // This is the PRs number
     #[serde(rename = "iid")]
     pub number: usize,
@@ -29,6 +72,16 @@ pub struct MergeRequest {
     #[serde(rename = "target_branch")]
     pub target_branch: String,
     pub web_url: String,
+    pub author: Author,
+    pub source_project_id: usize,
+    pub target_project_id: usize,
+    // RFC 3339 timestamp of when the MR was opened.
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Author {
+    pub username: String,
 }
 
 impl MergeRequest {
@@ -69,11 +122,32 @@ impl PullRequestId {
 
 pub struct GitLab {
     token: String,
+    base_url: String,
     client: reqwest::Client,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct AccessLevel {
+    pub access_level_description: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ProtectedBranch {
+    pub name: String,
+    pub push_access_levels: Vec<AccessLevel>,
+    pub merge_access_levels: Vec<AccessLevel>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Project {
+    // E.g. 'my/cool/project'.
+    pub path_with_namespace: String,
+    pub ssh_url_to_repo: String,
+}
+
 #[derive(Deserialize, Debug)]
 struct UserJson {
+    id: usize,
     username: String,
 }
 
@@ -86,40 +160,152 @@ impl GitLab {
         let token = env::var("GITLAB_TOKEN")?;
         Ok(Self {
             client: reqwest::Client::new(),
+            base_url: api_base_url(&host()),
             token,
         })
     }
 
     fn get(&self, endpoint: &str) -> reqwest::RequestBuilder {
         self.client
-            .get(format!("{GITLAB_BASE_URL}/{endpoint}"))
+            .get(format!("{}/{endpoint}", self.base_url))
             .header("PRIVATE-TOKEN", &self.token)
     }
 
+    /// GETs 'endpoint', retrying up to MAX_RETRIES times with exponential backoff on server
+    /// errors and connection problems. Only GETs are retried, since they are idempotent; retrying
+    /// a POST could create a merge request twice.
+    async fn get_with_retries(&self, endpoint: &str) -> Result<reqwest::Response> {
+        let mut delay = INITIAL_RETRY_DELAY;
+        let mut retries = 0;
+        loop {
+            let error = match self.get(endpoint).send().await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => {
+                    let status = response.status();
+                    let error =
+                        Error::general(format!("GitLab returned {} for {}.", status, endpoint));
+                    if !status.is_server_error() {
+                        return Err(error);
+                    }
+                    error
+                }
+                Err(err) if err.is_connect() || err.is_timeout() => {
+                    Error::general(format!("Could not reach GitLab for {}: {}", endpoint, err))
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if retries == MAX_RETRIES {
+                return Err(error);
+            }
+            retries += 1;
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+
     fn post(&self, endpoint: &str) -> reqwest::RequestBuilder {
         self.client
-            .post(format!("{GITLAB_BASE_URL}/{endpoint}"))
+            .post(format!("{}/{endpoint}", self.base_url))
+            .header("PRIVATE-TOKEN", &self.token)
+    }
+
+    fn put(&self, endpoint: &str) -> reqwest::RequestBuilder {
+        self.client
+            .put(format!("{}/{endpoint}", self.base_url))
             .header("PRIVATE-TOKEN", &self.token)
     }
 
     pub async fn find_user_name(&self) -> Result<String> {
-        let response = self.get("user").send().await?;
+        let response = self.get_with_retries("user").await?;
         let result: UserJson = response.json().await?;
         Ok(result.username)
     }
 
+    pub async fn find_user_id(&self) -> Result<usize> {
+        let response = self.get_with_retries("user").await?;
+        let result: UserJson = response.json().await?;
+        Ok(result.id)
+    }
+
     pub async fn search_mrs(&self, query: &str) -> Result<Vec<MergeRequest>> {
-        let response = self.get(&format!("merge_requests?{query}")).send().await?;
+        let response = self
+            .get_with_retries(&format!("merge_requests?{query}"))
+            .await?;
         Ok(response.json().await?)
     }
 
+    /// Returns the open merge requests that I am a reviewer of, in all projects.
+    pub async fn find_assigned_mrs(&self) -> Result<Vec<MergeRequest>> {
+        let user = self.find_user_name().await?;
+        // Without 'scope=all', only merge requests created by me are searched.
+        self.get_all_pages(&format!(
+            "merge_requests?reviewer_username={user}&state=opened&scope=all"
+        ))
+        .await
+    }
+
+    /// Fetches all pages of the list 'endpoint', which must already have a query string. GitLab
+    /// returns at most 100 items per page.
+    async fn get_all_pages<T: serde::de::DeserializeOwned>(
+        &self,
+        endpoint: &str,
+    ) -> Result<Vec<T>> {
+        let mut rv = Vec::new();
+        let mut page = "1".to_string();
+        loop {
+            let response = self
+                .get_with_retries(&format!("{endpoint}&per_page=100&page={page}"))
+                .await?;
+            let next_page = response
+                .headers()
+                .get("x-next-page")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("")
+                .to_string();
+            let items: Vec<T> = response.json().await?;
+            rv.extend(items);
+            if next_page.is_empty() {
+                return Ok(rv);
+            }
+            page = next_page;
+        }
+    }
+
     pub async fn get_mr(&self, project: &str, number: usize) -> Result<MergeRequest> {
         let response = self
-            .get(&format!(
+            .get_with_retries(&format!(
                 "projects/{}/merge_requests/{number}",
                 urlencode(project)
             ))
-            .send()
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn get_project(&self, id: usize) -> Result<Project> {
+        let response = self.get_with_retries(&format!("projects/{id}")).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Returns the usernames of all members of the group 'group', e.g. 'my/team'.
+    pub async fn get_group_members(&self, group: &str) -> Result<Vec<String>> {
+        let response = self
+            .get_with_retries(&format!("groups/{}/members", urlencode(group)))
+            .await?;
+        let members: Vec<UserJson> = response.json().await?;
+        Ok(members.into_iter().map(|m| m.username).collect())
+    }
+
+    pub async fn get_protected_branch(
+        &self,
+        project: &str,
+        branch: &str,
+    ) -> Result<ProtectedBranch> {
+        let response = self
+            .get_with_retries(&format!(
+                "projects/{}/protected_branches/{}",
+                urlencode(project),
+                urlencode(branch)
+            ))
             .await?;
         Ok(response.json().await?)
     }
@@ -131,12 +317,17 @@ impl GitLab {
         target_branch: &str,
         title: &str,
         description: &str,
+        labels: &[String],
     ) -> Result<MergeRequest> {
+        let labels = labels.join(",");
         let mut form = HashMap::new();
         form.insert("source_branch", source_branch);
         form.insert("target_branch", target_branch);
         form.insert("title", title);
         form.insert("description", description);
+        if !labels.is_empty() {
+            form.insert("labels", &labels);
+        }
 
         let response = self
             .post(&format!("projects/{}/merge_requests", urlencode(project)))
@@ -146,19 +337,141 @@ impl GitLab {
         let result: MergeRequest = response.json().await?;
         Ok(result)
     }
+
+    async fn put_mr(&self, project: &str, number: usize, form: &[(&str, String)]) -> Result<()> {
+        self.put(&format!(
+            "projects/{}/merge_requests/{number}",
+            urlencode(project)
+        ))
+        .form(form)
+        .send()
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    /// Marks the draft 'number' as ready by removing the draft prefix from its title and swaps
+    /// its labels. Labels that are not set are ignored by GitLab.
+    pub async fn mark_ready(
+        &self,
+        project: &str,
+        number: usize,
+        remove_labels: &[String],
+        add_labels: &[String],
+    ) -> Result<()> {
+        let mr = self.get_mr(project, number).await?;
+        let mut form = vec![("title", ready_title(&mr.title).to_string())];
+        if !remove_labels.is_empty() {
+            form.push(("remove_labels", remove_labels.join(",")));
+        }
+        if !add_labels.is_empty() {
+            form.push(("add_labels", add_labels.join(",")));
+        }
+        self.put_mr(project, number, &form).await
+    }
+
+    pub async fn update_mr(
+        &self,
+        project: &str,
+        number: usize,
+        title: &str,
+        description: &str,
+    ) -> Result<()> {
+        let form = [
+            ("title", title.to_string()),
+            ("description", description.to_string()),
+        ];
+        self.put_mr(project, number, &form).await
+    }
+
+    pub async fn set_assignees(
+        &self,
+        project: &str,
+        number: usize,
+        user_ids: &[usize],
+    ) -> Result<()> {
+        let form: Vec<_> = user_ids
+            .iter()
+            .map(|id| ("assignee_ids[]", id.to_string()))
+            .collect();
+        self.put_mr(project, number, &form).await
+    }
+
+    /// Requests reviews from 'usernames', which are resolved to user ids first.
+    pub async fn set_reviewers(
+        &self,
+        project: &str,
+        number: usize,
+        usernames: &[String],
+    ) -> Result<()> {
+        let mut form = Vec::new();
+        for username in usernames {
+            let response = self
+                .get_with_retries(&format!("users?username={}", urlencode(username)))
+                .await?;
+            let users: Vec<UserJson> = response.json().await?;
+            match users.first() {
+                Some(user) => form.push(("reviewer_ids[]", user.id.to_string())),
+                None => {
+                    return Err(Error::general(format!(
+                        "GitLab user {} does not exist.",
+                        username
+                    )))
+                }
+            }
+        }
+        self.put_mr(project, number, &form).await
+    }
+}
+
+/// Returns the merge request template of the repository in 'workdir'. That is the 'Default'
+/// template in '.gitlab/merge_request_templates', or the only template in there.
+pub fn get_merge_request_template(workdir: &Path) -> Option<String> {
+    let templates: Vec<_> = ::std::fs::read_dir(workdir.join(".gitlab/merge_request_templates"))
+        .ok()?
+        .filter_map(|f| f.ok().map(|d| d.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "md"))
+        .collect();
+    let template = templates
+        .iter()
+        .find(|p| {
+            p.file_stem()
+                .is_some_and(|s| s.to_string_lossy().eq_ignore_ascii_case("default"))
+        })
+        .or(match templates.as_slice() {
+            [only] => Some(only),
+            _ => None,
+        })?;
+    ::std::fs::read_to_string(template).ok()
+}
+
+/// Returns 'title' without the prefix that marks a merge request as draft.
+fn ready_title(title: &str) -> &str {
+    const DRAFT_PREFIXES: &[&str] = &["Draft:", "[Draft]", "(Draft)", "WIP:", "[WIP]"];
+    DRAFT_PREFIXES
+        .iter()
+        .find_map(|p| title.strip_prefix(p))
+        .unwrap_or(title)
+        .trim_start()
 }
 
 // I tried the GitLab crate, but it was very limiting, so gobbling together my own little Rest
 // abstraction was actually the easiest thing to do.
+//
+// Without an 'author', this finds the merge requests of the authenticated user.
 pub async fn find_my_mrs(
     start_date: DateTime<Local>,
     end_date: DateTime<Local>,
+    author: Option<&str>,
 ) -> Result<Vec<MergeRequest>> {
     let gl = GitLab::new()?;
     let start = start_date.format("%Y-%m-%dT%H:%M:%SZ").to_string();
     let end = end_date.format("%Y-%m-%dT%H:%M:%SZ").to_string();
 
-    let user = gl.find_user_name().await?;
+    let user = match author {
+        Some(author) => author.to_string(),
+        None => gl.find_user_name().await?,
+    };
     let mrs = gl
         .search_mrs(&format!(
             "author_username={user}&created_after={start}&created_before={end}"
@@ -166,3 +479,66 @@ pub async fn find_my_mrs(
         .await?;
     Ok(mrs)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{api_base_url, get_merge_request_template, resolve_host};
+    use std::fs;
+
+    #[test]
+    fn test_get_merge_request_template() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(get_merge_request_template(dir.path()), None);
+
+        let templates = dir.path().join(".gitlab/merge_request_templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("Bug.md"), "bug").unwrap();
+        assert_eq!(
+            get_merge_request_template(dir.path()),
+            Some("bug".to_string())
+        );
+
+        fs::write(templates.join("Feature.md"), "feature").unwrap();
+        assert_eq!(get_merge_request_template(dir.path()), None);
+
+        fs::write(templates.join("Default.md"), "default").unwrap();
+        assert_eq!(
+            get_merge_request_template(dir.path()),
+            Some("default".to_string())
+        );
+    }
+
+    #[test]
+    fn test_api_base_url() {
+        assert_eq!(
+            api_base_url(&resolve_host(None, None)),
+            "https://gitlab.com/api/v4"
+        );
+        assert_eq!(
+            api_base_url(&resolve_host(
+                Some("gitlab.internal.corp".to_string()),
+                None
+            )),
+            "https://gitlab.internal.corp/api/v4"
+        );
+        assert_eq!(
+            api_base_url(&resolve_host(
+                None,
+                Some("gitlab.internal.corp/".to_string())
+            )),
+            "https://gitlab.internal.corp/api/v4"
+        );
+        // The environment wins over the git config.
+        assert_eq!(
+            resolve_host(
+                Some("env.corp".to_string()),
+                Some("config.corp".to_string())
+            ),
+            "env.corp"
+        );
+        assert_eq!(
+            resolve_host(Some("".to_string()), Some("config.corp".to_string())),
+            "config.corp"
+        );
+    }
+}
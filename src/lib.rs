@@ -1,9 +1,15 @@
+mod bitbucket;
+pub mod completions;
+mod config;
 pub mod diffbase;
 pub mod dispatch;
 pub mod error;
 pub mod git;
 mod github;
 mod gitlab;
+mod prompt;
+#[cfg(test)]
+mod test_util;
 
 pub use crate::diffbase::Diffbase;
 pub use crate::error::Error;
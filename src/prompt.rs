@@ -0,0 +1,43 @@
+/// Tools to interactively ask the user for input on the terminal.
+use super::error::{Error, Result};
+use std::io::{self, IsTerminal, Write};
+
+/// Prints 'question' and reads a line from stdin. Returns the trimmed answer. Fails if stdin is not
+/// a terminal, since nobody could answer and the next line of the input would be taken instead.
+fn ask(question: &str) -> Result<String> {
+    if !io::stdin().is_terminal() {
+        return Err(Error::general(format!(
+            "Can not ask '{}', stdin is not a terminal.",
+            question
+        )));
+    }
+    print!("{} ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+/// Asks a yes/no 'question'. An empty answer selects 'default'.
+pub fn confirm(question: &str, default: bool) -> Result<bool> {
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+    let answer = ask(&format!("{} {}", question, hint))?.to_lowercase();
+    Ok(match answer.as_str() {
+        "" => default,
+        "y" | "yes" => true,
+        _ => false,
+    })
+}
+
+/// Prints 'items' as a numbered list and asks the user to choose one of them. Returns the index of
+/// the chosen item.
+pub fn pick(question: &str, items: &[&str]) -> Result<usize> {
+    for (i, item) in items.iter().enumerate() {
+        println!("  [{}] {}", i + 1, item);
+    }
+    let answer = ask(&format!("{} [1-{}]", question, items.len()))?;
+    match answer.parse::<usize>() {
+        Ok(n) if n >= 1 && n <= items.len() => Ok(n - 1),
+        _ => Err(Error::general(format!("Invalid selection: '{}'.", answer))),
+    }
+}
@@ -0,0 +1,51 @@
+//! Helpers shared by the tests of several modules.
+
+/// A repository in a temporary directory, whose commits all have the empty tree.
+pub struct TestRepo {
+    pub repo: git2::Repository,
+    // Deleted when dropped, so it has to live as long as the repository.
+    pub dir: tempfile::TempDir,
+}
+
+impl TestRepo {
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        TestRepo { repo, dir }
+    }
+
+    /// Creates a commit with 'message' on top of 'parents' and points 'update_ref', e.g. "HEAD",
+    /// to it.
+    pub fn commit(
+        &self,
+        update_ref: Option<&str>,
+        message: &str,
+        parents: &[git2::Oid],
+    ) -> git2::Oid {
+        let signature = git2::Signature::now("Tester", "tester@example.com").unwrap();
+        let tree = self
+            .repo
+            .find_tree(self.repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let parents: Vec<git2::Commit> = parents
+            .iter()
+            .map(|p| self.repo.find_commit(*p).unwrap())
+            .collect();
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+        self.repo
+            .commit(update_ref, &signature, &signature, message, &tree, &parents)
+            .unwrap()
+    }
+
+    /// Creates a commit with 'message' on top of HEAD, if there is one, and moves HEAD to it.
+    pub fn commit_on_head(&self, message: &str) -> git2::Oid {
+        let head: Vec<git2::Oid> = self
+            .repo
+            .head()
+            .ok()
+            .and_then(|h| h.target())
+            .into_iter()
+            .collect();
+        self.commit(Some("HEAD"), message, &head)
+    }
+}